@@ -1,24 +1,36 @@
 use std::io;
 use std::io::Write;
-use std::net::IpAddr;
-use std::ops::Deref;
-use std::str::FromStr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use anyhow::Result;
+use serde::Serialize;
+use structopt::clap::ErrorKind::{HelpDisplayed, VersionDisplayed};
 use structopt::StructOpt;
 
-use libdnscheck::{count_lists, Output, Query};
+use libdnscheck::{
+    parse_nameserver, read_queries, Config, DnsCheckError, ExitStatus, Family, Format,
+    ListDefinition, ListReport, Output, Overrides, Query, QueryReport, ResolverKind, Role, Tally,
+};
 
 fn main() {
+    // We need to know how to exit even if we can't make sense of the rest of the arguments
+    let exit_status = std::env::args_os().any(|arg| arg == "--exit-status");
     let err = main_().unwrap_err();
     if let Some(err) = err.downcast_ref::<structopt::clap::Error>() {
         let out = io::stdout();
         writeln!(&mut out.lock(), "{}", err.message).expect("Error writing Error to stdout");
+        if matches!(err.kind, HelpDisplayed | VersionDisplayed) {
+            std::process::exit(0)
+        }
     } else {
         eprintln!("Error: {:?}", err);
     }
-    // We exit with a "success" on error, as the error code is the count of the number of lists hit
-    // so `0` is the fail-safe
+    if exit_status {
+        std::process::exit(ExitStatus::TotalFailure.code())
+    }
+    // Otherwise we exit with a "success" on error, as the error code is the count of the number of
+    // lists hit so `0` is the fail-safe
     std::process::exit(0)
 }
 
@@ -27,11 +39,16 @@ fn main() {
 struct Arguments {
     #[structopt(short, long, number_of_values = 1, help = "A DNS block list")]
     /// The base name of a DNS block list, either IP or domain-based depending on the query you pass in.
-    block: Vec<String>,
+    /// Follow it with the answers that count as listings and their labels to ignore any others,
+    /// like `zen.spamhaus.org:2=SBL,4-7=XBL`.  For lists that set a bit in the answer for each
+    /// sub-list, name the bits instead: `multi.surbl.org:&8=PH,&16=MW,&64=ABUSE,&128=CR`.  A list
+    /// named like `file:/etc/dabl/blocked' is read from a local file instead of DNS.
+    block: Vec<ListDefinition>,
     #[structopt(short, long, number_of_values = 1, help = "A DNS allow list")]
     /// The base name of a DNS block list, either IP or domain-based depending on the query you pass in.
-    /// If an allow list matches, we won't check any block lists.
-    allow: Vec<String>,
+    /// If an allow list matches, we won't check any block lists.  Takes return codes in the same way
+    /// as --block.
+    allow: Vec<ListDefinition>,
     #[structopt(
         short,
         long,
@@ -40,16 +57,90 @@ struct Arguments {
     )]
     /// Output information about every DNS lookup made and every result returned.
     verbose: bool,
+    #[structopt(
+        long = "set",
+        number_of_values = 1,
+        help = "A named set of lists from the configuration file"
+    )]
+    /// Check the allow and block lists in a set defined in the configuration file, as well as any
+    /// given with -a or -b.  If you don't give any lists at all, we'll use the set called
+    /// `default' if there is one.
+    sets: Vec<String>,
+    #[structopt(long, parse(from_os_str), help = "Read configuration from this file")]
+    /// Read configuration from this file, instead of /etc/dnscheck/config.toml and
+    /// ~/.config/dnscheck/config.toml.
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Block queries that score at least this much")]
+    /// Add up the weights of the block lists each query is found in, rather than counting them,
+    /// and block any query whose score reaches this threshold.  Lists have a weight of 1 unless
+    /// the configuration file says otherwise.  We'll exit with 1 if any query is blocked, and 0
+    /// otherwise.
+    threshold: Option<f64>,
+    #[structopt(short, long, help = "How many lookups to run at once [default: 8]")]
+    /// The maximum number of DNS lookups to have in flight at any one time.
+    jobs: Option<usize>,
+    #[structopt(long, help = "Seconds to wait for each lookup [default: 30]")]
+    /// How long to wait for any single DNS lookup before treating it as an error.
+    timeout: Option<u64>,
+    #[structopt(long, help = "Which answers to accept: a, aaaa or both [default: a]")]
+    /// Which kinds of address record to accept as answers from lists.  Almost every list answers
+    /// with A records, but some newer lists publish AAAA records instead.
+    family: Option<Family>,
+    #[structopt(
+        long,
+        help = "How to resolve names: auto, resolved or system [default: auto]"
+    )]
+    /// Which resolver to query lists with: `resolved' uses systemd-resolved over D-Bus, `system'
+    /// uses the C library's resolver, and `auto' uses systemd-resolved if it's available.
+    resolver: Option<ResolverKind>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = parse_nameserver),
+        conflicts_with = "resolver",
+        help = "Send queries directly to this nameserver"
+    )]
+    /// Send DNS queries directly to this nameserver (an address, optionally with a port) instead
+    /// of going through the system's resolver.  Some lists refuse queries that come from public
+    /// resolvers, so you may want to point this at a local recursive resolver.  If given more than
+    /// once, we'll try each nameserver in turn.
+    nameserver: Vec<SocketAddr>,
+    #[structopt(
+        long,
+        default_value = "text",
+        help = "How to print results: text, json or jsonl"
+    )]
+    /// Print results as text, as a single JSON array once every query has been checked, or as JSON
+    /// Lines with an object for each query as soon as it's been checked.  JSON results include
+    /// every list we checked, with its answers, reasons, timings and any error.
+    format: Format,
+    #[structopt(
+        long,
+        help = "Exit with a code for the outcome, rather than the number of listings"
+    )]
+    /// Exit with 0 if nothing was listed, 1 if anything was listed (or blocked, with --threshold),
+    /// 2 if nothing was listed but something was on an allow list, 3 if nothing was listed but
+    /// some lookups failed, and 4 if every lookup failed or we couldn't check anything at all.
+    /// Failed lookups are reported as warnings rather than stopping us.
+    exit_status: bool,
     #[structopt(short, long, conflicts_with = "verbose", help = "Only output errors")]
     /// Only output when something fails unexpectedly
     quiet: bool,
-    #[structopt(help = "An IP address (v4 or v6) or domain name")]
-    /// An IP address (v4 or v6) or domain name to check against the DNS lists provided with -a or -b
-    query: String,
+    #[structopt(
+        required = true,
+        help = "IP addresses (v4 or v6) or domain names, or `-' to read from standard input"
+    )]
+    /// IP addresses (v4 or v6) or domain names to check against the DNS lists provided with -a or
+    /// -b.  Specify `-' to read one query per line from standard input, skipping blank lines and
+    /// lines starting with `#'.  A network like `192.0.2.0/24' checks every address in it, up to
+    /// 256 of them; IPv6 networks are checked a /64 at a time.
+    query: Vec<String>,
 }
 
 fn main_() -> Result<()> {
     let args: Arguments = Arguments::from_args_safe()?;
+    let format = args.format;
+    let exit_status = args.exit_status;
 
     let output = if args.quiet {
         Output::Quiet
@@ -59,63 +150,211 @@ fn main_() -> Result<()> {
         Output::Normal
     };
 
-    let allow: Vec<&str> = args.allow.iter().map(Deref::deref).collect();
-    let block: Vec<&str> = args.block.iter().map(Deref::deref).collect();
+    let config = Config::load(args.config.as_deref())?;
+    let defaults = &config.defaults;
 
-    if output != Output::Quiet {
-        println!(
-            "Allow: {}\nBlock: {}\nQuery: {}",
-            allow.join(", "),
-            block.join(", "),
-            args.query
-        );
+    let (allow, block) = config.lists(&args.sets, &args.allow, &args.block)?;
+    let allow = &allow;
+    let block = &block;
+
+    if output != Output::Quiet && format == Format::Text {
+        println!("Allow: {}\nBlock: {}", names(allow), names(block));
     }
 
-    let query: Query = match IpAddr::from_str(&args.query) {
-        Ok(ip) => Query::Address(ip),
-        _ => Query::Domain(&args.query),
+    let checker = config.checker(
+        output,
+        Overrides {
+            jobs: args.jobs,
+            timeout: args.timeout,
+            family: args.family,
+            resolver: args.resolver,
+            nameservers: args.nameserver,
+        },
+    )?;
+    let threshold = args.threshold.or(defaults.threshold);
+
+    let reasons = format != Format::Text;
+
+    let mut hits = 0;
+    let mut blocked = false;
+    let mut tally = Tally::default();
+    let mut verdicts = vec![];
+    let mut report = |query: Result<Query, DnsCheckError>| -> Result<()> {
+        let query = match query {
+            Ok(query) => query,
+            // One bad line on standard input shouldn't stop us checking the rest
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                tally.fail();
+                return Ok(());
+            }
+        };
+        let report = checker.check(query, allow, block, reasons);
+        let score = report.score();
+        hits += report.blocks().len() as i32;
+        blocked |= threshold.is_some_and(|threshold| score >= threshold);
+        let listed = match threshold {
+            Some(threshold) => score >= threshold,
+            None => !report.blocks().is_empty(),
+        };
+        tally.add(&report, listed);
+
+        let verdict = Verdict {
+            allowed: report.allowed(),
+            score,
+            blocked: threshold.map(|threshold| score >= threshold),
+            report,
+        };
+        match format {
+            Format::Text => print_text(verdict.report, threshold, output, exit_status)?,
+            Format::JsonLines => println!("{}", serde_json::to_string(&verdict)?),
+            Format::Json => verdicts.push(verdict),
+        }
+        Ok(())
     };
+    // Turn down a network we can't check before we've printed anything for the others
+    let queries = args
+        .query
+        .iter()
+        .map(|param| match param.as_str() {
+            "-" => Ok(None),
+            param => Query::parse(param, allow.iter().chain(block)).map(Some),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for query in queries {
+        match query {
+            Some(query) => report(Ok(query))?,
+            None => {
+                for line in read_queries(io::stdin().lock()) {
+                    let line = line?;
+                    report(Query::parse(&line, allow.iter().chain(block)))?;
+                }
+            }
+        }
+    }
+    if format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&verdicts)?);
+    }
 
-    let allows: Vec<_> = count_lists(&[query], &allow, output)?
-        .into_iter()
-        .filter(|m| m.found)
-        .collect();
+    if exit_status {
+        std::process::exit(tally.status().code());
+    }
+    if threshold.is_some() {
+        std::process::exit(blocked as i32);
+    }
+    // Exit codes only have eight bits, and a count that wrapped to 0 would look clean
+    std::process::exit(hits.min(255));
+}
+
+/// Prints what we found for a query, or returns the first error we hit unless we've been asked to
+/// keep going.
+fn print_text(
+    mut report: QueryReport,
+    threshold: Option<f64>,
+    output: Output,
+    keep_going: bool,
+) -> Result<()> {
+    // A failed lookup could make "not found" untrue, so we won't say anything until we've checked
+    let mut failed = false;
+    for list in &mut report.lists {
+        match list.error.take() {
+            // A list that refused to answer hasn't told us anything either way
+            Some(e @ DnsCheckError::Refused { .. }) => eprintln!("Warning: {}", e),
+            Some(e) if keep_going => {
+                eprintln!("Warning: {}", e);
+                failed = true;
+            }
+            Some(e) => return Err(e.into()),
+            None => {}
+        }
+    }
+
+    let allowed = report.allowed();
+    let blocks = report.blocks();
+    let total = report.score();
+    let mut allows = vec![];
+    for list in &report.lists {
+        if list.role == Role::Allow && list.found {
+            allows.push(list);
+        }
+    }
 
-    if !allows.is_empty() {
-        if output != Output::Quiet {
+    if output != Output::Quiet {
+        if allowed {
             println!(
-                "Found in {} allow lists: {}",
+                "{}: found in {} allow lists: {}",
+                report.query,
                 allows.len(),
                 allows
-                    .into_iter()
-                    .map(|m| m.list)
+                    .iter()
+                    .map(|l| describe(l))
                     .collect::<Vec<_>>()
                     .join(", ")
             )
-        }
-        std::process::exit(0)
-    }
-
-    let blocks: Vec<_> = count_lists(&[query], &block, output)?
-        .into_iter()
-        .filter(|m| m.found)
-        .collect();
-
-    if !blocks.is_empty() {
-        let len = blocks.len() as i32;
-        if output != Output::Quiet {
+        } else if blocks.is_empty() && failed {
+            println!("{}: not found, but some lookups failed", report.query);
+        } else if blocks.is_empty() {
+            println!("{}: not found", report.query);
+        } else if let Some(threshold) = threshold {
             println!(
-                "Found in {} block lists: {}",
-                len,
+                "{}: {} with a score of {} against a threshold of {}: {}",
+                report.query,
+                if total >= threshold {
+                    "blocked"
+                } else {
+                    "not blocked"
+                },
+                total,
+                threshold,
                 blocks
-                    .into_iter()
-                    .map(|m| m.list)
+                    .iter()
+                    .map(|l| format!("{} scored {}", describe(l), l.score))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        } else {
+            println!(
+                "{}: found in {} block lists: {}",
+                report.query,
+                blocks.len(),
+                blocks
+                    .iter()
+                    .map(|l| describe(l))
                     .collect::<Vec<_>>()
                     .join(", ")
             )
         }
-        std::process::exit(len)
     }
+    Ok(())
+}
+
+/// What we'll report for each query in JSON.
+#[derive(Serialize)]
+struct Verdict {
+    #[serde(flatten)]
+    report: QueryReport,
+    allowed: bool,
+    score: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blocked: Option<bool>,
+}
+
+fn describe(list: &ListReport) -> String {
+    let found = if !list.labels.is_empty() {
+        list.labels.join(", ")
+    } else {
+        let answers: Vec<String> = list.answers.iter().map(|a| a.to_string()).collect();
+        answers.join(", ")
+    };
+    // Only networks have addresses listed in them
+    match list.listed.len() {
+        0 => format!("{} ({})", list.list, found),
+        1 => format!("{} ({}) at {}", list.list, found, list.listed[0]),
+        n => format!("{} ({}) at {} addresses", list.list, found, n),
+    }
+}
 
-    std::process::exit(0);
+fn names(lists: &[ListDefinition]) -> String {
+    let names: Vec<&str> = lists.iter().map(|l| l.name.as_str()).collect();
+    names.join(", ")
 }
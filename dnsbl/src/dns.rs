@@ -0,0 +1,374 @@
+//! The server's side of the DNS wire format (RFC 1035): reading questions and writing answers.
+
+use std::net::Ipv4Addr;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const HEADER_LEN: usize = 12;
+
+/// The longest response we'll send over UDP, without EDNS.
+pub const UDP_LIMIT: usize = 512;
+
+/// A query's single question.
+#[derive(Debug, Clone)]
+pub struct Question {
+    pub id: u16,
+    recursion_desired: bool,
+    /// The name, lower-cased and without the trailing dot.
+    pub name: String,
+    pub qtype: u16,
+    /// The question exactly as it was asked, to repeat in the response.
+    raw: Vec<u8>,
+}
+
+/// Why we can't answer a query, and the ID to give the error response if there is one.
+#[derive(Debug, Clone, Copy)]
+pub struct Unanswerable {
+    pub id: Option<u16>,
+    pub rcode: u8,
+}
+
+/// A resource record to add to a response, always in class IN.
+#[derive(Debug, Clone)]
+pub enum Record {
+    A(Ipv4Addr),
+    Txt(String),
+    Soa {
+        zone: String,
+        serial: u32,
+        minimum: u32,
+    },
+}
+
+/// Reads a query, which must hold exactly one question about class IN.
+pub fn parse_query(buf: &[u8]) -> Result<Question, Unanswerable> {
+    if buf.len() < HEADER_LEN {
+        return Err(Unanswerable {
+            id: None,
+            rcode: RCODE_FORMERR,
+        });
+    }
+    let id = u16::from_be_bytes([buf[0], buf[1]]);
+    let flags = u16::from_be_bytes([buf[2], buf[3]]);
+    let fail = |rcode| Unanswerable {
+        id: Some(id),
+        rcode,
+    };
+    if flags & FLAG_QR != 0 {
+        // Never answer a response, or two servers could keep each other busy forever
+        return Err(Unanswerable {
+            id: None,
+            rcode: RCODE_FORMERR,
+        });
+    }
+    if (flags >> 11) & 0xF != 0 {
+        return Err(fail(RCODE_NOTIMP));
+    }
+    if u16::from_be_bytes([buf[4], buf[5]]) != 1 {
+        return Err(fail(RCODE_FORMERR));
+    }
+
+    let mut labels = vec![];
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| fail(RCODE_FORMERR))? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // A query has nothing earlier to point back to
+        if len > 63 {
+            return Err(fail(RCODE_FORMERR));
+        }
+        let label = buf.get(pos..pos + len).ok_or_else(|| fail(RCODE_FORMERR))?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+    let fixed = buf.get(pos..pos + 4).ok_or_else(|| fail(RCODE_FORMERR))?;
+    let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+    if qclass != CLASS_IN {
+        return Err(fail(RCODE_REFUSED));
+    }
+
+    Ok(Question {
+        id,
+        recursion_desired: flags & FLAG_RD != 0,
+        name: labels.join("."),
+        qtype,
+        raw: buf[HEADER_LEN..pos + 4].to_vec(),
+    })
+}
+
+/// An error response, without the question if we couldn't read it.
+pub fn error_response(id: u16, question: Option<&Question>, rcode: u8) -> Vec<u8> {
+    let mut buf = header(id, question, rcode, 0, 0);
+    if let Some(question) = question {
+        buf.extend_from_slice(&question.raw);
+    }
+    buf
+}
+
+/// An authoritative response.  Answers are about the name in the question, and the authority
+/// section is for the SOA record that tells resolvers how long to remember there's no answer.
+///
+/// If the response won't fit in `limit` bytes, we send it without any records and mark it
+/// truncated, so the client asks again over TCP.
+pub fn response(
+    question: &Question,
+    rcode: u8,
+    ttl: u32,
+    answers: &[Record],
+    authority: Option<&Record>,
+    limit: usize,
+) -> Vec<u8> {
+    let mut buf = header(
+        question.id,
+        Some(question),
+        rcode,
+        answers.len() as u16,
+        authority.is_some() as u16,
+    );
+    buf[2] |= (FLAG_AA >> 8) as u8;
+    buf.extend_from_slice(&question.raw);
+    for answer in answers {
+        // The question's name always starts straight after the header
+        buf.extend_from_slice(&(0xC000 | HEADER_LEN as u16).to_be_bytes());
+        write_record(&mut buf, answer, ttl);
+    }
+    if let Some(record @ Record::Soa { zone, .. }) = authority {
+        write_name(&mut buf, zone);
+        write_record(&mut buf, record, ttl);
+    }
+
+    if buf.len() > limit {
+        let mut buf = header(question.id, Some(question), rcode, 0, 0);
+        buf[2] |= ((FLAG_AA | FLAG_TC) >> 8) as u8;
+        buf.extend_from_slice(&question.raw);
+        return buf;
+    }
+    buf
+}
+
+fn header(
+    id: u16,
+    question: Option<&Question>,
+    rcode: u8,
+    answers: u16,
+    authority: u16,
+) -> Vec<u8> {
+    let mut flags = FLAG_QR | u16::from(rcode & 0xF);
+    if question.is_some_and(|q| q.recursion_desired) {
+        flags |= FLAG_RD;
+    }
+    let mut buf = Vec::with_capacity(UDP_LIMIT);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&(question.is_some() as u16).to_be_bytes());
+    buf.extend_from_slice(&answers.to_be_bytes());
+    buf.extend_from_slice(&authority.to_be_bytes());
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf
+}
+
+/// Writes everything after the owner name.
+fn write_record(buf: &mut Vec<u8>, record: &Record, ttl: u32) {
+    let mut data = vec![];
+    let rtype = match record {
+        Record::A(address) => {
+            data.extend_from_slice(&address.octets());
+            TYPE_A
+        }
+        Record::Txt(text) => {
+            // Each string in a TXT record holds at most 255 bytes
+            let text = text.as_bytes();
+            if text.is_empty() {
+                data.push(0);
+            }
+            for chunk in text.chunks(255) {
+                data.push(chunk.len() as u8);
+                data.extend_from_slice(chunk);
+            }
+            TYPE_TXT
+        }
+        Record::Soa {
+            zone,
+            serial,
+            minimum,
+        } => {
+            write_name(&mut data, zone);
+            write_name(&mut data, &format!("hostmaster.{}", zone));
+            for value in &[*serial, 3600, 600, 86400, *minimum] {
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+            TYPE_SOA
+        }
+    };
+    buf.extend_from_slice(&rtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    buf.extend_from_slice(&ttl.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&data);
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query with the given flags and question count, asking about `name`.
+    fn query(flags: u16, questions: u16, name: &[u8], qtype: u16, qclass: u16) -> Vec<u8> {
+        let mut buf = vec![0xAB, 0xCD];
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&questions.to_be_bytes());
+        buf.extend_from_slice(&[0; 6]);
+        buf.extend_from_slice(name);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&qclass.to_be_bytes());
+        buf
+    }
+
+    const NAME: &[u8] = b"\x012\x010\x010\x03127\x02BL\x04test\x00";
+
+    fn question() -> Question {
+        parse_query(&query(FLAG_RD, 1, NAME, TYPE_A, CLASS_IN)).unwrap()
+    }
+
+    fn rcode(buf: &[u8]) -> Result<Question, (Option<u16>, u8)> {
+        parse_query(buf).map_err(|e| (e.id, e.rcode))
+    }
+
+    fn counts(buf: &[u8]) -> [u16; 4] {
+        let count = |i: usize| u16::from_be_bytes([buf[4 + i * 2], buf[5 + i * 2]]);
+        [count(0), count(1), count(2), count(3)]
+    }
+
+    #[test]
+    fn questions_are_read_in_lower_case() {
+        let question = question();
+        assert_eq!(question.id, 0xABCD);
+        assert_eq!(question.name, "2.0.0.127.bl.test");
+        assert_eq!(question.qtype, TYPE_A);
+        assert!(question.recursion_desired);
+        assert_eq!(question.raw.len(), NAME.len() + 4);
+    }
+
+    #[test]
+    fn queries_we_cant_answer_say_why() {
+        let id = Some(0xABCD);
+        assert_eq!(rcode(&[0; 11]).unwrap_err(), (None, RCODE_FORMERR));
+        // Never answer a response
+        let response = query(FLAG_QR, 1, NAME, TYPE_A, CLASS_IN);
+        assert_eq!(rcode(&response).unwrap_err(), (None, RCODE_FORMERR));
+        let notify = query(4 << 11, 1, NAME, TYPE_A, CLASS_IN);
+        assert_eq!(rcode(&notify).unwrap_err(), (id, RCODE_NOTIMP));
+        let two = query(0, 2, NAME, TYPE_A, CLASS_IN);
+        assert_eq!(rcode(&two).unwrap_err(), (id, RCODE_FORMERR));
+        let pointer = query(0, 1, b"\xC0\x0C", TYPE_A, CLASS_IN);
+        assert_eq!(rcode(&pointer).unwrap_err(), (id, RCODE_FORMERR));
+        let chaos = query(0, 1, NAME, TYPE_TXT, 3);
+        assert_eq!(rcode(&chaos).unwrap_err(), (id, RCODE_REFUSED));
+        let short = query(0, 1, NAME, TYPE_A, CLASS_IN);
+        assert_eq!(
+            rcode(&short[..short.len() - 2]).unwrap_err(),
+            (id, RCODE_FORMERR)
+        );
+    }
+
+    #[test]
+    fn error_responses_repeat_the_question_if_they_can() {
+        let question = question();
+        let with = error_response(question.id, Some(&question), RCODE_REFUSED);
+        assert_eq!(&with[..4], &[0xAB, 0xCD, 0x81, 0x05]);
+        assert_eq!(counts(&with), [1, 0, 0, 0]);
+        assert_eq!(&with[HEADER_LEN..], &question.raw[..]);
+
+        let without = error_response(0x1234, None, RCODE_FORMERR);
+        assert_eq!(without, [0x12, 0x34, 0x80, 0x01, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn answers_point_back_at_the_question() {
+        let question = question();
+        let answers = [Record::A(Ipv4Addr::new(127, 0, 0, 2))];
+        let buf = response(&question, 0, 300, &answers, None, UDP_LIMIT);
+        // An authoritative answer, with recursion desired copied from the query
+        assert_eq!(&buf[2..4], &[0x85, 0x00]);
+        assert_eq!(counts(&buf), [1, 1, 0, 0]);
+        let answer = &buf[HEADER_LEN + question.raw.len()..];
+        assert_eq!(
+            answer,
+            [0xC0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 127, 0, 0, 2]
+        );
+    }
+
+    #[test]
+    fn not_found_comes_with_the_zones_soa() {
+        let question = question();
+        let soa = Record::Soa {
+            zone: "bl.test".to_string(),
+            serial: 7,
+            minimum: 60,
+        };
+        let buf = response(&question, RCODE_NXDOMAIN, 60, &[], Some(&soa), UDP_LIMIT);
+        assert_eq!(buf[3] & 0xF, RCODE_NXDOMAIN);
+        assert_eq!(counts(&buf), [1, 0, 1, 0]);
+        let authority = &buf[HEADER_LEN + question.raw.len()..];
+        let mut expected = b"\x02bl\x04test\x00\x00\x06\x00\x01\x00\x00\x00\x3c".to_vec();
+        let mut data = b"\x02bl\x04test\x00\x0ahostmaster\x02bl\x04test\x00".to_vec();
+        for value in &[7u32, 3600, 600, 86400, 60] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        expected.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        expected.extend_from_slice(&data);
+        assert_eq!(authority, &expected[..]);
+    }
+
+    #[test]
+    fn long_txt_records_are_split_into_strings() {
+        let question = question();
+        let long = "x".repeat(300);
+        let buf = response(&question, 0, 60, &[Record::Txt(long)], None, 1024);
+        let data = &buf[HEADER_LEN + question.raw.len() + 12..];
+        assert_eq!(data.len(), 302);
+        assert_eq!(data[0], 255);
+        assert_eq!(data[256], 45);
+
+        let buf = response(&question, 0, 60, &[Record::Txt(String::new())], None, 1024);
+        assert_eq!(&buf[buf.len() - 3..], &[0, 1, 0]);
+    }
+
+    #[test]
+    fn responses_too_big_to_send_are_truncated() {
+        let question = question();
+        let answers = vec![Record::Txt("x".repeat(600))];
+        let buf = response(&question, 0, 60, &answers, None, UDP_LIMIT);
+        // Truncated, without any records, so the client asks again over TCP
+        assert_eq!(&buf[2..4], &[0x87, 0x00]);
+        assert_eq!(counts(&buf), [1, 0, 0, 0]);
+        assert_eq!(buf.len(), HEADER_LEN + question.raw.len());
+
+        let buf = response(&question, 0, 60, &answers, None, u16::MAX as usize);
+        assert_eq!(counts(&buf), [1, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use structopt::clap::ErrorKind::{HelpDisplayed, VersionDisplayed};
+use structopt::StructOpt;
+
+use libdnscheck::{list_query, DnsCheckError, Zone, ZoneFormat};
+
+use crate::dns::{
+    error_response, parse_query, response, Question, Record, RCODE_NXDOMAIN, RCODE_REFUSED, TYPE_A,
+    TYPE_ANY, TYPE_SOA, TYPE_TXT, UDP_LIMIT,
+};
+
+mod dns;
+
+/// How often we look for changes to the list files.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+fn main() {
+    if let Err(err) = main_() {
+        if let Some(err) = err.downcast_ref::<structopt::clap::Error>() {
+            let out = io::stdout();
+            writeln!(&mut out.lock(), "{}", err.message).expect("Error writing Error to stdout");
+            if matches!(err.kind, HelpDisplayed | VersionDisplayed) {
+                std::process::exit(0)
+            }
+        } else {
+            eprintln!("Error: {:?}", err);
+        }
+        // Unlike dabl, there's nobody to mistake this for a result
+        std::process::exit(1)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt()]
+struct Arguments {
+    #[structopt(short, long, help = "The address and port to answer queries on")]
+    /// The address and port to answer queries on, over both UDP and TCP, like `127.0.0.1:5353'.
+    listen: SocketAddr,
+    #[structopt(
+        short,
+        long = "zone",
+        number_of_values = 1,
+        required = true,
+        help = "A zone to serve, as name:format:file[,file...]"
+    )]
+    /// A zone to serve, with the rbldnsd format and files of its entries, like
+    /// `bl.example.com:ip4set:/etc/dabl/blocked'.  Give the same zone more than once to serve
+    /// entries in different formats together.
+    zones: Vec<ZoneSpec>,
+    #[structopt(
+        long,
+        default_value = "2100",
+        help = "Seconds resolvers may keep our answers"
+    )]
+    /// Seconds resolvers may keep our answers, including that a name isn't listed.
+    ttl: u32,
+    #[structopt(short, long, help = "Log every query")]
+    verbose: bool,
+}
+
+/// One `--zone` argument.
+#[derive(Debug, Clone)]
+struct ZoneSpec {
+    name: String,
+    format: ZoneFormat,
+    files: Vec<PathBuf>,
+}
+
+impl FromStr for ZoneSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (name, format, files) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(format), Some(files)) if !name.is_empty() && !files.is_empty() => {
+                (name, format, files)
+            }
+            _ => return Err(format!("expected name:format:file, not {:?}", s)),
+        };
+        Ok(ZoneSpec {
+            name: name.trim_matches('.').to_ascii_lowercase(),
+            format: format.parse()?,
+            files: files.split(',').map(PathBuf::from).collect(),
+        })
+    }
+}
+
+/// A zone we're serving, and where its entries came from.
+struct Served {
+    name: String,
+    files: Vec<(PathBuf, ZoneFormat)>,
+    modified: Vec<Option<SystemTime>>,
+    serial: u32,
+    zone: Zone,
+}
+
+impl Served {
+    fn load(name: String, files: Vec<(PathBuf, ZoneFormat)>) -> Result<Served, DnsCheckError> {
+        let mut zone = Zone::default();
+        for (path, format) in &files {
+            zone.read(path, *format)?;
+        }
+        let modified = modified(&files);
+        let serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as u32);
+        Ok(Served {
+            name,
+            files,
+            modified,
+            serial,
+            zone,
+        })
+    }
+
+    fn is_stale(&self) -> bool {
+        modified(&self.files) != self.modified
+    }
+}
+
+fn modified(files: &[(PathBuf, ZoneFormat)]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|(path, _)| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Everything each query needs to be answered.
+struct Server {
+    zones: RwLock<Vec<Arc<Served>>>,
+    ttl: u32,
+    verbose: bool,
+}
+
+fn main_() -> Result<()> {
+    let args: Arguments = Arguments::from_args_safe()?;
+
+    let mut files: BTreeMap<String, Vec<(PathBuf, ZoneFormat)>> = BTreeMap::new();
+    for spec in args.zones {
+        let format = spec.format;
+        files
+            .entry(spec.name)
+            .or_default()
+            .extend(spec.files.into_iter().map(|f| (f, format)));
+    }
+    let zones = files
+        .into_iter()
+        .map(|(name, files)| Served::load(name, files).map(Arc::new))
+        .collect::<Result<_, _>>()?;
+
+    let server = Arc::new(Server {
+        zones: RwLock::new(zones),
+        ttl: args.ttl,
+        verbose: args.verbose,
+    });
+
+    let udp = UdpSocket::bind(args.listen)?;
+    let tcp = TcpListener::bind(args.listen)?;
+
+    let reloader = Arc::clone(&server);
+    thread::spawn(move || loop {
+        thread::sleep(RELOAD_INTERVAL);
+        reloader.reload();
+    });
+
+    let tcp_server = Arc::clone(&server);
+    thread::spawn(move || {
+        for stream in tcp.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = Arc::clone(&tcp_server);
+                    thread::spawn(move || {
+                        if let Err(e) = server.serve_tcp(stream) {
+                            eprintln!("Error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+    });
+
+    let mut buf = [0; UDP_LIMIT];
+    loop {
+        let (len, peer) = udp.recv_from(&mut buf)?;
+        if let Some(reply) = server.handle(&buf[..len], UDP_LIMIT) {
+            if let Err(e) = udp.send_to(&reply, peer) {
+                eprintln!("Error: replying to {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+impl Server {
+    /// Reloads any zone whose files have changed, keeping the old entries if the new ones won't
+    /// load.
+    fn reload(&self) {
+        let current = self.zones.read().expect("Zones lock poisoned").clone();
+        let mut reloaded = Vec::with_capacity(current.len());
+        let mut changed = false;
+        for served in current {
+            if !served.is_stale() {
+                reloaded.push(served);
+                continue;
+            }
+            match Served::load(served.name.clone(), served.files.clone()) {
+                Ok(fresh) => {
+                    eprintln!("Reloaded {}", fresh.name);
+                    reloaded.push(Arc::new(fresh));
+                    changed = true;
+                }
+                Err(e) => {
+                    eprintln!("Error: reloading {}: {}", served.name, e);
+                    reloaded.push(served);
+                }
+            }
+        }
+        if changed {
+            *self.zones.write().expect("Zones lock poisoned") = reloaded;
+        }
+    }
+
+    /// Answers queries over a TCP connection, each with a two-byte length in front, until the
+    /// client closes it.
+    fn serve_tcp(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        loop {
+            let mut len = [0; 2];
+            match stream.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query)?;
+            if let Some(reply) = self.handle(&query, u16::MAX as usize) {
+                let mut message = (reply.len() as u16).to_be_bytes().to_vec();
+                message.extend_from_slice(&reply);
+                stream.write_all(&message)?;
+            }
+        }
+    }
+
+    /// The response to a query, if it deserves one.
+    fn handle(&self, query: &[u8], limit: usize) -> Option<Vec<u8>> {
+        match parse_query(query) {
+            Ok(question) => Some(self.answer(&question, limit)),
+            Err(e) => e.id.map(|id| error_response(id, None, e.rcode)),
+        }
+    }
+
+    fn answer(&self, question: &Question, limit: usize) -> Vec<u8> {
+        let zones = self.zones.read().expect("Zones lock poisoned");
+        // The most specific zone wins, if one sits inside another
+        let served = zones
+            .iter()
+            .filter(|z| question.name == z.name || question.name.ends_with(&format!(".{}", z.name)))
+            .max_by_key(|z| z.name.len());
+        let served = match served {
+            Some(served) => served,
+            None => {
+                if self.verbose {
+                    eprintln!("{}: not one of our zones", question.name);
+                }
+                return error_response(question.id, Some(question), RCODE_REFUSED);
+            }
+        };
+
+        let soa = Record::Soa {
+            zone: served.name.clone(),
+            serial: served.serial,
+            minimum: self.ttl,
+        };
+        let not_found = |rcode| response(question, rcode, self.ttl, &[], Some(&soa), limit);
+
+        if question.name == served.name {
+            return if matches!(question.qtype, TYPE_SOA | TYPE_ANY) {
+                response(
+                    question,
+                    0,
+                    self.ttl,
+                    std::slice::from_ref(&soa),
+                    None,
+                    limit,
+                )
+            } else {
+                not_found(0)
+            };
+        }
+
+        let prefix = &question.name[..question.name.len() - served.name.len() - 1];
+        let query = list_query(prefix);
+        let listing = match served.zone.lookup(&query) {
+            Some(listing) => listing,
+            None => {
+                if self.verbose {
+                    eprintln!("{}: {} is not listed", served.name, query);
+                }
+                return not_found(RCODE_NXDOMAIN);
+            }
+        };
+        if self.verbose {
+            eprintln!("{}: {} is listed as {}", served.name, query, listing.answer);
+        }
+
+        let mut answers = vec![];
+        if matches!(question.qtype, TYPE_A | TYPE_ANY) {
+            answers.push(Record::A(listing.answer));
+        }
+        if matches!(question.qtype, TYPE_TXT | TYPE_ANY) {
+            answers.extend(listing.reason(&query.to_string()).map(Record::Txt));
+        }
+        if answers.is_empty() {
+            return not_found(0);
+        }
+        response(question, 0, self.ttl, &answers, None, limit)
+    }
+}
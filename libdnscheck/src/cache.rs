@@ -0,0 +1,230 @@
+//! Remembers what lists have told us, for as long as they said we could.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::Answer;
+
+/// How many answers we'll remember unless we're told otherwise.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// The answers we've had from each list for each query, until their TTL runs out.
+///
+/// A list's answer that a query isn't listed is cached for the negative caching time from the
+/// list's SOA record.  The system resolver never tells us a TTL, though, and resolved doesn't for
+/// a name that doesn't exist, which is most answers.  Those are remembered for the default TTL
+/// we're given, or not at all without one.
+///
+/// A full cache forgets the answers that have expired to make room, and if none have, it stops
+/// remembering new answers until some do.
+#[derive(Debug)]
+pub struct Cache {
+    capacity: usize,
+    default_ttl: Option<Duration>,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    expires: Instant,
+    /// Whether the list gave us the TTL, rather than it being our default.
+    reported: bool,
+    addresses: Vec<IpAddr>,
+}
+
+impl Cache {
+    /// Remembers at most `capacity` answers at once, and nothing at all with a capacity of zero.
+    pub fn new(capacity: usize) -> Cache {
+        Cache {
+            capacity,
+            default_ttl: None,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remembers answers that don't come with a TTL for this long.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Cache {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// The answer a list last gave us for a query, if it hasn't expired, with however long it has
+    /// left if the list told us its TTL.
+    pub fn get(&self, list: &str, query: &str) -> Option<Answer> {
+        let mut entries = self.entries.lock().ok()?;
+        let key = (list.to_string(), query.to_string());
+        let now = Instant::now();
+        match entries.get(&key) {
+            Some(entry) if entry.expires > now => Some(Answer {
+                addresses: entry.addresses.clone(),
+                // TTLs are whole seconds, so we'll round down to match
+                ttl: Some(Duration::from_secs((entry.expires - now).as_secs()))
+                    .filter(|_| entry.reported),
+            }),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remembers a list's answer for a query, if it has a TTL or we have a default one.
+    pub fn insert(&self, list: &str, query: &str, answer: &Answer) {
+        let expires = match answer
+            .ttl
+            .or(self.default_ttl)
+            .filter(|ttl| *ttl > Duration::ZERO)
+            .and_then(|ttl| Instant::now().checked_add(ttl))
+        {
+            Some(expires) => expires,
+            None => return,
+        };
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let key = (list.to_string(), query.to_string());
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+            // Rather than choosing what to forget, we'll just stop remembering anything new
+            if entries.len() >= self.capacity {
+                return;
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                expires,
+                reported: answer.ttl.is_some(),
+                addresses: answer.addresses.clone(),
+            },
+        );
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn answer(ttl: Option<Duration>) -> Answer {
+        Answer {
+            addresses: vec!["127.0.0.2".parse().unwrap()],
+            ttl,
+        }
+    }
+
+    fn size(cache: &Cache) -> usize {
+        cache.entries.lock().unwrap().len()
+    }
+
+    #[test]
+    fn answers_are_remembered_with_the_time_they_have_left() {
+        let cache = Cache::default();
+        cache.insert("list", "2.0.0.127", &answer(Some(Duration::from_secs(300))));
+        let cached = cache.get("list", "2.0.0.127").unwrap();
+        assert_eq!(cached.addresses, answer(None).addresses);
+        let ttl = cached.ttl.unwrap();
+        assert!(ttl <= Duration::from_secs(300) && ttl >= Duration::from_secs(299));
+        assert!(cache.get("other", "2.0.0.127").is_none());
+        assert!(cache.get("list", "1.0.0.127").is_none());
+    }
+
+    #[test]
+    fn expired_answers_are_forgotten() {
+        let cache = Cache::default();
+        cache.insert(
+            "list",
+            "2.0.0.127",
+            &answer(Some(Duration::from_millis(20))),
+        );
+        assert!(cache.get("list", "2.0.0.127").is_some());
+        sleep(Duration::from_millis(40));
+        assert!(cache.get("list", "2.0.0.127").is_none());
+        assert_eq!(size(&cache), 0);
+    }
+
+    #[test]
+    fn answers_that_a_query_isnt_listed_are_remembered() {
+        let cache = Cache::default();
+        let unlisted = Answer {
+            addresses: vec![],
+            ttl: Some(Duration::from_secs(60)),
+        };
+        cache.insert("list", "1.0.0.127", &unlisted);
+        assert!(cache.get("list", "1.0.0.127").unwrap().addresses.is_empty());
+    }
+
+    #[test]
+    fn answers_without_a_ttl_need_a_default() {
+        let cache = Cache::default();
+        cache.insert("list", "2.0.0.127", &answer(None));
+        assert!(cache.get("list", "2.0.0.127").is_none());
+
+        let cache = Cache::default().with_default_ttl(Duration::from_secs(60));
+        cache.insert("list", "2.0.0.127", &answer(None));
+        let cached = cache.get("list", "2.0.0.127").unwrap();
+        assert_eq!(cached.addresses, answer(None).addresses);
+        assert_eq!(cached.ttl, None);
+    }
+
+    #[test]
+    fn answers_with_a_zero_ttl_arent_remembered() {
+        let cache = Cache::default().with_default_ttl(Duration::from_secs(60));
+        cache.insert("list", "2.0.0.127", &answer(Some(Duration::ZERO)));
+        assert!(cache.get("list", "2.0.0.127").is_none());
+
+        let cache = Cache::default().with_default_ttl(Duration::ZERO);
+        cache.insert("list", "2.0.0.127", &answer(None));
+        assert!(cache.get("list", "2.0.0.127").is_none());
+    }
+
+    #[test]
+    fn a_full_cache_stops_remembering_new_answers() {
+        let cache = Cache::new(2);
+        let ttl = Some(Duration::from_secs(60));
+        cache.insert("list", "1.0.0.127", &answer(ttl));
+        cache.insert("list", "2.0.0.127", &answer(ttl));
+        cache.insert("list", "3.0.0.127", &answer(ttl));
+        assert!(cache.get("list", "1.0.0.127").is_some());
+        assert!(cache.get("list", "2.0.0.127").is_some());
+        assert!(cache.get("list", "3.0.0.127").is_none());
+
+        // but it still updates what it already has
+        cache.insert("list", "2.0.0.127", &answer(Some(Duration::from_secs(600))));
+        assert!(cache.get("list", "2.0.0.127").unwrap().ttl > Some(Duration::from_secs(500)));
+    }
+
+    #[test]
+    fn a_full_cache_makes_room_by_forgetting_expired_answers() {
+        let cache = Cache::new(2);
+        cache.insert(
+            "list",
+            "1.0.0.127",
+            &answer(Some(Duration::from_millis(20))),
+        );
+        cache.insert("list", "2.0.0.127", &answer(Some(Duration::from_secs(60))));
+        sleep(Duration::from_millis(40));
+        cache.insert("list", "3.0.0.127", &answer(Some(Duration::from_secs(60))));
+        assert_eq!(size(&cache), 2);
+        assert!(cache.get("list", "2.0.0.127").is_some());
+        assert!(cache.get("list", "3.0.0.127").is_some());
+    }
+
+    #[test]
+    fn an_empty_cache_remembers_nothing() {
+        let cache = Cache::new(0);
+        cache.insert("list", "2.0.0.127", &answer(Some(Duration::from_secs(60))));
+        assert!(cache.get("list", "2.0.0.127").is_none());
+    }
+}
@@ -0,0 +1,791 @@
+//! A handle for making many lookups, which only sets up its resolver once.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::executor::{run, Limits};
+use crate::network;
+use crate::{
+    list_hostname, Answer, AutoResolver, Cache, DnsCheckError, DnsListMembership, Family,
+    ListDefinition, ListReport, Output, Query, QueryReport, Resolver, Role, Zone, ZoneFormat,
+};
+
+/// When to stop checking any more lists.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum StopAfter {
+    /// Check every query in every list.
+    #[default]
+    Never,
+    /// Stop checking a query once it's been found in any list.
+    QueryFound,
+    /// Stop checking every query once any of them has been found in any list.
+    AnyFound,
+}
+
+/// The outcome of looking a query up in one list.
+#[derive(Debug)]
+pub struct Lookup<'a> {
+    pub query: Query<'a>,
+    pub list: &'a ListDefinition,
+    /// How long the lookup took.
+    pub elapsed: Duration,
+    pub result: Result<DnsListMembership, DnsCheckError>,
+}
+
+pub struct Checker {
+    output: Output,
+    limits: Limits,
+    family: Family,
+    stop: StopAfter,
+    cache: Option<Cache>,
+    /// File-backed lists, read the first time we need them.
+    zones: Mutex<HashMap<PathBuf, Loaded>>,
+    resolver: Box<dyn Resolver>,
+}
+
+/// A list file's entries, and when the file was modified when we read them.
+struct Loaded {
+    modified: Option<SystemTime>,
+    zone: Arc<Zone>,
+}
+
+impl Checker {
+    /// Connects to systemd-resolved if we can, otherwise we'll use the system resolver for every
+    /// lookup.
+    pub fn new(output: Output, limits: Limits) -> Checker {
+        let resolver = AutoResolver::connect(output, limits.timeout);
+        Checker::with_resolver(output, limits, Box::new(resolver))
+    }
+
+    /// Makes every lookup using the given resolver.
+    pub fn with_resolver(output: Output, limits: Limits, resolver: Box<dyn Resolver>) -> Checker {
+        Checker {
+            output,
+            limits,
+            family: Family::default(),
+            stop: StopAfter::default(),
+            cache: None,
+            zones: Mutex::default(),
+            resolver,
+        }
+    }
+
+    /// Chooses which kinds of address record we'll accept as answers, whichever resolver we use.
+    pub fn with_family(mut self, family: Family) -> Checker {
+        self.family = family;
+        self
+    }
+
+    /// Chooses whether to skip the remaining lookups once we've found a listing.
+    pub fn with_stop_after(mut self, stop: StopAfter) -> Checker {
+        self.stop = stop;
+        self
+    }
+
+    /// Remembers each list's answers for as long as their TTL allows, rather than asking again.
+    pub fn with_cache(mut self, cache: Cache) -> Checker {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn lookup(
+        &self,
+        list: &ListDefinition,
+        query: &Query,
+    ) -> Result<DnsListMembership, DnsCheckError> {
+        if let Some(path) = &list.file {
+            let listing = self.zone(path)?.lookup(query).cloned();
+            if self.output == Output::Verbose {
+                eprintln!("Source: {:?}, Query: {:?}", path, query);
+                eprintln!("Result: {:?}", listing);
+            }
+            let answers = listing.map(|l| IpAddr::V4(l.answer)).into_iter().collect();
+            return Ok(membership(list, query, answers, None));
+        }
+
+        let source = list.name.as_str();
+        let key = query.to_string();
+
+        let cached = self.cache.as_ref().and_then(|c| c.get(source, &key));
+        let answer = match cached {
+            Some(answer) => {
+                if self.output == Output::Verbose {
+                    eprintln!("Source: {:?}, Query: {:?}", source, query);
+                    eprintln!("Cached: {:?}", answer);
+                }
+                answer
+            }
+            None => {
+                let answer = self.resolve(source, query)?;
+                if let Some(cache) = &self.cache {
+                    cache.insert(source, &key, &answer);
+                }
+                answer
+            }
+        };
+
+        let answers: Vec<_> = answer
+            .addresses
+            .into_iter()
+            .filter(|ip| self.family.matches(ip))
+            .collect();
+
+        // Some operators answer with an error code that looks just like a listing
+        for answer in &answers {
+            if let Some(sentinel) = list.sentinels.iter().find(|s| s.matches(answer)) {
+                return Err(DnsCheckError::Refused {
+                    list: source.to_string(),
+                    answer: *answer,
+                    reason: sentinel.reason.clone(),
+                });
+            }
+        }
+
+        Ok(membership(list, query, answers, answer.ttl))
+    }
+
+    /// The entries in a list file, which we read again whenever it changes.  If the new entries
+    /// won't load, we keep using the old ones until they do.
+    fn zone(&self, path: &Path) -> Result<Arc<Zone>, DnsCheckError> {
+        let mut zones = self
+            .zones
+            .lock()
+            .map_err(|_| DnsCheckError::Unknown(anyhow::anyhow!("list files lock poisoned")))?;
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let loaded = match zones.get(path) {
+            Some(loaded) if loaded.modified == modified => return Ok(Arc::clone(&loaded.zone)),
+            Some(loaded) => Some(Arc::clone(&loaded.zone)),
+            None => None,
+        };
+        let zone = match (Zone::load(path, ZoneFormat::Mixed), loaded) {
+            (Ok(zone), _) => Arc::new(zone),
+            (Err(e), Some(zone)) => {
+                if self.output != Output::Quiet {
+                    eprintln!("Warning: reloading {}: {}", path.display(), e);
+                }
+                return Ok(zone);
+            }
+            (Err(e), None) => return Err(e),
+        };
+        let loaded = Loaded {
+            modified,
+            zone: Arc::clone(&zone),
+        };
+        zones.insert(path.to_path_buf(), loaded);
+        Ok(zone)
+    }
+
+    fn resolve(&self, source: &str, query: &Query) -> Result<Answer, DnsCheckError> {
+        let hostname = list_hostname(source, query);
+
+        if self.output == Output::Verbose {
+            eprintln!("Source: {:?}, Query: {:?}", source, query);
+            eprintln!("Querying: {}", hostname);
+        }
+
+        let result = self.resolver.resolve_answer(&hostname, self.family);
+
+        if self.output == Output::Verbose {
+            eprintln!("Result: {:?}", result);
+        }
+
+        result
+    }
+
+    /// Fetches the TXT records a list publishes for a query, which usually explain why it's
+    /// listed.
+    pub fn lookup_txt(&self, source: &str, query: &Query) -> Result<Vec<String>, DnsCheckError> {
+        let hostname = list_hostname(source, query);
+
+        if self.output == Output::Verbose {
+            eprintln!("Querying TXT: {}", hostname);
+        }
+
+        let result = self.resolver.resolve_txt(&hostname);
+
+        if self.output == Output::Verbose {
+            eprintln!("Result: {:?}", result);
+        }
+
+        result
+    }
+
+    /// Explains why a list has a query: its TXT records, or the reason in a list file.
+    pub fn lookup_reason(
+        &self,
+        list: &ListDefinition,
+        query: &Query,
+    ) -> Result<Vec<String>, DnsCheckError> {
+        match &list.file {
+            Some(path) => Ok(self
+                .zone(path)?
+                .lookup(query)
+                .and_then(|l| l.reason(&query.to_string()))
+                .into_iter()
+                .collect()),
+            None => self.lookup_txt(&list.name, query),
+        }
+    }
+
+    /// Looks up every query in every list that accepts it, returning a result for each lookup.
+    ///
+    /// Lookups run concurrently, but results come back in the same order as a sequential check
+    /// would produce them: all the lists for the first query, then all the lists for the
+    /// second, and so on.  A network is looked up an address at a time, with one result for the
+    /// whole network in each list.
+    ///
+    /// If we've been asked to stop after finding a listing, every lookup after the first listing
+    /// in that order comes back as skipped, just as if we'd checked one list at a time.  We don't
+    /// start lookups once we know they'll be skipped.
+    pub fn check_lists<'a>(
+        &self,
+        queries: &[Query<'a>],
+        lists: &'a [ListDefinition],
+    ) -> Vec<Lookup<'a>> {
+        // Each lookup we'll make, with nothing to look up for a network that's too large
+        let mut jobs: Vec<(usize, &ListDefinition, Option<Query>)> = vec![];
+        let mut groups: Vec<(usize, &Query, &ListDefinition, usize)> = vec![];
+        for (index, query) in queries.iter().enumerate() {
+            for list in lists.iter().filter(|list| list.query.accepts(query)) {
+                let targets = match *query {
+                    Query::Network(network, prefix) => {
+                        match network::addresses(network, prefix, list.ipv6_prefix) {
+                            Some(addresses) => addresses
+                                .into_iter()
+                                .map(Query::Address)
+                                .map(Some)
+                                .collect(),
+                            None => vec![None],
+                        }
+                    }
+                    query => vec![Some(query)],
+                };
+                groups.push((index, query, list, targets.len()));
+                jobs.extend(targets.into_iter().map(|target| (index, list, target)));
+            }
+        }
+        let found: Vec<AtomicBool> = queries.iter().map(|_| AtomicBool::new(false)).collect();
+
+        let mut results = run(&jobs, self.limits.parallelism, |&(index, list, target)| {
+            let start = Instant::now();
+            let found = match self.stop {
+                StopAfter::Never => None,
+                StopAfter::QueryFound => Some(&found[index]),
+                StopAfter::AnyFound => Some(&found[0]),
+            };
+
+            let result = match target {
+                None => Err(DnsCheckError::TooLarge(queries[index].to_string())),
+                Some(target) if found.is_some_and(|f| f.load(Ordering::SeqCst)) => {
+                    Ok(DnsListMembership {
+                        skipped: true,
+                        ..membership(list, &target, vec![], None)
+                    })
+                }
+                Some(target) => self.lookup(list, &target),
+            };
+            if let (Some(found), Ok(membership)) = (found, &result) {
+                if membership.found {
+                    found.store(true, Ordering::SeqCst);
+                }
+            }
+            (start.elapsed(), result)
+        })
+        .into_iter();
+
+        // Lookups that were already running when we found a listing still finish, but we only
+        // keep what a sequential check would have seen
+        let mut stopped = vec![false; queries.len()];
+        groups
+            .into_iter()
+            .map(|(index, query, list, count)| {
+                let results: Vec<_> = results.by_ref().take(count).collect();
+                let elapsed = results.iter().map(|(elapsed, _)| *elapsed).sum();
+                let result = match query {
+                    Query::Network(..) => combine(query, list, results.into_iter().map(|(_, r)| r)),
+                    _ => {
+                        let (_, result) = results.into_iter().next().expect("one lookup per list");
+                        result
+                    }
+                };
+                let stop = match self.stop {
+                    StopAfter::Never => None,
+                    StopAfter::QueryFound => Some(&mut stopped[index]),
+                    StopAfter::AnyFound => Some(&mut stopped[0]),
+                };
+                let result = match (stop, result) {
+                    (Some(true), _) => Ok(DnsListMembership {
+                        skipped: true,
+                        ..membership(list, query, vec![], None)
+                    }),
+                    (Some(stop), Ok(membership)) => {
+                        *stop = membership.found;
+                        Ok(membership)
+                    }
+                    (_, result) => result,
+                };
+                Lookup {
+                    query: *query,
+                    list,
+                    elapsed,
+                    result,
+                }
+            })
+            .collect()
+    }
+
+    /// Checks a query against the allow lists, and then the block lists unless an allow list has
+    /// it.  With `reasons`, we also fetch the TXT records explaining each listing.
+    pub fn check(
+        &self,
+        query: Query,
+        allow: &[ListDefinition],
+        block: &[ListDefinition],
+        reasons: bool,
+    ) -> QueryReport {
+        let mut lists = self.reports(query, allow, Role::Allow, reasons);
+        if !lists.iter().any(|l| l.found) {
+            lists.extend(self.reports(query, block, Role::Block, reasons));
+        }
+        QueryReport {
+            query: query.to_string(),
+            lists,
+        }
+    }
+
+    fn reports(
+        &self,
+        query: Query,
+        lists: &[ListDefinition],
+        role: Role,
+        reasons: bool,
+    ) -> Vec<ListReport> {
+        self.check_lists(&[query], lists)
+            .into_iter()
+            .map(|lookup| {
+                let reason = match &lookup.result {
+                    // A listing without a reason is still a listing
+                    // A network has no reason of its own, but the first address listed in it does
+                    Ok(m) if reasons && m.found => {
+                        let about = m.listed.first().map_or(query, |ip| Query::Address(*ip));
+                        self.lookup_reason(lookup.list, &about).unwrap_or_default()
+                    }
+                    _ => vec![],
+                };
+                ListReport::new(role, lookup, reason)
+            })
+            .collect()
+    }
+
+    pub fn count_lists(
+        &self,
+        queries: &[Query],
+        lists: &[ListDefinition],
+    ) -> Result<Vec<DnsListMembership>, DnsCheckError> {
+        self.check_lists(queries, lists)
+            .into_iter()
+            .map(|lookup| lookup.result)
+            .collect()
+    }
+}
+
+fn membership(
+    list: &ListDefinition,
+    query: &Query,
+    answers: Vec<IpAddr>,
+    ttl: Option<Duration>,
+) -> DnsListMembership {
+    let found = answers.iter().any(|a| list.lists(a));
+    DnsListMembership {
+        name: query.to_string(),
+        list: list.name.clone(),
+        found,
+        skipped: false,
+        labels: list.labels(&answers),
+        answers,
+        score: if found { list.weight } else { 0.0 },
+        ttl,
+        listed: vec![],
+    }
+}
+
+/// One result for a whole network from the results for each address in it.  Any listing makes
+/// the network listed, even if other lookups failed; otherwise, any failure fails the lot.
+fn combine(
+    query: &Query,
+    list: &ListDefinition,
+    results: impl Iterator<Item = Result<DnsListMembership, DnsCheckError>>,
+) -> Result<DnsListMembership, DnsCheckError> {
+    let mut combined = DnsListMembership {
+        skipped: true,
+        ..membership(list, query, vec![], None)
+    };
+    let mut error = None;
+    let mut ttls = vec![];
+    for result in results {
+        let membership = match result {
+            Ok(membership) => membership,
+            Err(e) => {
+                error.get_or_insert(e);
+                continue;
+            }
+        };
+        if membership.skipped {
+            continue;
+        }
+        combined.skipped = false;
+        ttls.push(membership.ttl);
+        if !membership.found {
+            continue;
+        }
+        combined.found = true;
+        if let Ok(address) = membership.name.parse() {
+            combined.listed.push(address);
+        }
+        for answer in membership.answers {
+            if !combined.answers.contains(&answer) {
+                combined.answers.push(answer);
+            }
+        }
+        for label in membership.labels {
+            if !combined.labels.contains(&label) {
+                combined.labels.push(label);
+            }
+        }
+    }
+    if let Some(e) = error.filter(|_| !combined.found) {
+        return Err(e);
+    }
+    // The network's answer is only as good as the answer that expires first
+    combined.ttl = ttls
+        .into_iter()
+        .collect::<Option<Vec<Duration>>>()
+        .and_then(|ttls| ttls.into_iter().min());
+    combined.score = if combined.found { list.weight } else { 0.0 };
+    Ok(combined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sentinel;
+
+    /// Answers from a table instead of DNS, keeping track of every name it's asked about.
+    #[derive(Default)]
+    struct Mock {
+        answers: HashMap<String, Vec<IpAddr>>,
+        failing: Vec<String>,
+        asked: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Mock {
+        fn listed(mut self, query: &str, list: &str, answers: &[&str]) -> Mock {
+            let answers = answers.iter().map(|a| a.parse().unwrap()).collect();
+            let name = list_hostname(list, &Query::from(query));
+            self.answers.insert(name, answers);
+            self
+        }
+
+        fn failing(mut self, query: &str, list: &str) -> Mock {
+            self.failing.push(list_hostname(list, &Query::from(query)));
+            self
+        }
+    }
+
+    impl Resolver for Mock {
+        fn resolve(&self, hostname: &str, family: Family) -> Result<Vec<IpAddr>, DnsCheckError> {
+            self.resolve_answer(hostname, family).map(|a| a.addresses)
+        }
+
+        fn resolve_answer(&self, hostname: &str, _: Family) -> Result<Answer, DnsCheckError> {
+            self.asked.lock().unwrap().push(hostname.to_string());
+            if self.failing.iter().any(|name| name == hostname) {
+                return Err(DnsCheckError::Timeout(hostname.to_string()));
+            }
+            Ok(Answer {
+                addresses: self.answers.get(hostname).cloned().unwrap_or_default(),
+                ttl: Some(Duration::from_secs(300)),
+            })
+        }
+
+        fn resolve_txt(&self, hostname: &str) -> Result<Vec<String>, DnsCheckError> {
+            Ok(vec![format!("{} is listed", hostname)])
+        }
+    }
+
+    fn checker(mock: Mock, parallelism: usize) -> Checker {
+        let limits = Limits {
+            parallelism,
+            timeout: Duration::from_secs(1),
+        };
+        Checker::with_resolver(Output::Quiet, limits, Box::new(mock))
+    }
+
+    fn lists(names: &[&str]) -> Vec<ListDefinition> {
+        names.iter().map(|name| name.parse().unwrap()).collect()
+    }
+
+    /// Each lookup as `query list outcome`.
+    fn outcomes(lookups: &[Lookup]) -> Vec<String> {
+        lookups
+            .iter()
+            .map(|lookup| {
+                let outcome = match &lookup.result {
+                    Ok(m) if m.skipped => "skipped",
+                    Ok(m) if m.found => "listed",
+                    Ok(_) => "clean",
+                    Err(_) => "failed",
+                };
+                format!("{} {} {}", lookup.query, lookup.list.name, outcome)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn results_come_back_in_order_of_query_then_list() {
+        let mock = Mock::default()
+            .listed("192.0.2.1", "b.test", &["127.0.0.2"])
+            .listed("192.0.2.2", "c.test", &["127.0.0.2"])
+            .failing("192.0.2.2", "a.test");
+        let lists = lists(&["a.test", "b.test", "c.test"]);
+        let queries = [Query::from("192.0.2.1"), Query::from("192.0.2.2")];
+        let lookups = checker(mock, 8).check_lists(&queries, &lists);
+        assert_eq!(
+            outcomes(&lookups),
+            [
+                "192.0.2.1 a.test clean",
+                "192.0.2.1 b.test listed",
+                "192.0.2.1 c.test clean",
+                "192.0.2.2 a.test failed",
+                "192.0.2.2 b.test clean",
+                "192.0.2.2 c.test listed",
+            ]
+        );
+    }
+
+    #[test]
+    fn stopping_after_a_query_is_found_skips_its_remaining_lists() {
+        let mock = Mock::default()
+            .listed("192.0.2.1", "b.test", &["127.0.0.2"])
+            .listed("192.0.2.1", "c.test", &["127.0.0.2"])
+            .listed("192.0.2.2", "a.test", &["127.0.0.2"]);
+        let asked = Arc::clone(&mock.asked);
+        let lists = lists(&["a.test", "b.test", "c.test"]);
+        let queries = [Query::from("192.0.2.1"), Query::from("192.0.2.2")];
+        let checker = checker(mock, 1).with_stop_after(StopAfter::QueryFound);
+        assert_eq!(
+            outcomes(&checker.check_lists(&queries, &lists)),
+            [
+                "192.0.2.1 a.test clean",
+                "192.0.2.1 b.test listed",
+                "192.0.2.1 c.test skipped",
+                "192.0.2.2 a.test listed",
+                "192.0.2.2 b.test skipped",
+                "192.0.2.2 c.test skipped",
+            ]
+        );
+        // One at a time, we never start a lookup we'd skip
+        assert_eq!(
+            *asked.lock().unwrap(),
+            [
+                "1.2.0.192.a.test.",
+                "1.2.0.192.b.test.",
+                "2.2.0.192.a.test."
+            ]
+        );
+    }
+
+    #[test]
+    fn stopping_after_anything_is_found_skips_later_queries_even_if_they_ran() {
+        let mock = Mock::default()
+            .listed("192.0.2.1", "a.test", &["127.0.0.2"])
+            .listed("192.0.2.2", "a.test", &["127.0.0.2"])
+            .listed("192.0.2.2", "b.test", &["127.0.0.2"]);
+        let lists = lists(&["a.test", "b.test"]);
+        let queries = [Query::from("192.0.2.1"), Query::from("192.0.2.2")];
+        let checker = checker(mock, 8).with_stop_after(StopAfter::AnyFound);
+        assert_eq!(
+            outcomes(&checker.check_lists(&queries, &lists)),
+            [
+                "192.0.2.1 a.test listed",
+                "192.0.2.1 b.test skipped",
+                "192.0.2.2 a.test skipped",
+                "192.0.2.2 b.test skipped",
+            ]
+        );
+    }
+
+    #[test]
+    fn sentinel_answers_are_refusals() {
+        let mock = Mock::default().listed("192.0.2.1", "zen.spamhaus.org", &["127.255.255.254"]);
+        let list = ListDefinition::new("zen.spamhaus.org");
+        match checker(mock, 1).lookup(&list, &Query::from("192.0.2.1")) {
+            Err(DnsCheckError::Refused { answer, reason, .. }) => {
+                assert_eq!(answer, IpAddr::from([127, 255, 255, 254]));
+                assert_eq!(reason, "query via public/open resolver");
+            }
+            result => panic!("expected a refusal, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn lists_choose_their_own_sentinels() {
+        let mock = Mock::default()
+            .listed("192.0.2.1", "zen.spamhaus.org", &["127.255.255.254"])
+            .listed("192.0.2.1", "bl.test", &["127.0.0.5"]);
+        let checker = checker(mock, 1);
+        let query = Query::from("192.0.2.1");
+
+        let none = ListDefinition {
+            sentinels: vec![],
+            ..ListDefinition::new("zen.spamhaus.org")
+        };
+        assert!(checker.lookup(&none, &query).unwrap().found);
+
+        let own = ListDefinition {
+            sentinels: vec![Sentinel::parse("4-6", "over quota").unwrap()],
+            ..ListDefinition::new("bl.test")
+        };
+        match checker.lookup(&own, &query) {
+            Err(DnsCheckError::Refused { reason, .. }) => assert_eq!(reason, "over quota"),
+            result => panic!("expected a refusal, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn return_codes_decide_listings_and_label_them() {
+        let mock = Mock::default()
+            .listed("192.0.2.1", "zen.test", &["127.0.0.4", "127.0.0.5"])
+            .listed("192.0.2.2", "zen.test", &["127.0.0.10"]);
+        let checker = checker(mock, 1);
+        let list: ListDefinition = "zen.test:2=SBL,4-7=XBL".parse().unwrap();
+        let list = ListDefinition {
+            weight: 3.0,
+            ..list
+        };
+
+        let listed = checker.lookup(&list, &Query::from("192.0.2.1")).unwrap();
+        assert!(listed.found);
+        assert_eq!(listed.labels, ["XBL"]);
+        assert_eq!(listed.score, 3.0);
+
+        let unknown = checker.lookup(&list, &Query::from("192.0.2.2")).unwrap();
+        assert!(!unknown.found);
+        assert!(unknown.labels.is_empty());
+        assert_eq!(unknown.score, 0.0);
+        assert_eq!(unknown.answers, [IpAddr::from([127, 0, 0, 10])]);
+    }
+
+    #[test]
+    fn bitmasks_label_every_sub_list_that_matches() {
+        let mock = Mock::default()
+            .listed("spam.example", "multi.test", &["127.0.0.72"])
+            .listed("odd.example", "multi.test", &["127.0.1.8"]);
+        let checker = checker(mock, 1);
+        let list: ListDefinition = "multi.test:&8=PH,&16=MW,&0x40=ABUSE".parse().unwrap();
+
+        let listed = checker.lookup(&list, &Query::from("spam.example")).unwrap();
+        assert!(listed.found);
+        assert_eq!(listed.labels, ["PH", "ABUSE"]);
+
+        // Only 127.0.0.x answers carry bits
+        let odd = checker.lookup(&list, &Query::from("odd.example")).unwrap();
+        assert!(!odd.found);
+    }
+
+    #[test]
+    fn a_network_is_listed_if_any_address_in_it_is() {
+        let mock = Mock::default()
+            .listed("192.0.2.1", "bl.test", &["127.0.0.2"])
+            .listed("192.0.2.3", "bl.test", &["127.0.0.3", "127.0.0.2"])
+            .failing("192.0.2.2", "bl.test");
+        let lists = lists(&["bl.test:2=SPAM,3=VIRUS"]);
+        let lookups = checker(mock, 8).check_lists(&[Query::from("192.0.2.0/30")], &lists);
+        assert_eq!(lookups.len(), 1);
+        let network = lookups[0].result.as_ref().unwrap();
+        assert_eq!(network.name, "192.0.2.0/30");
+        assert!(network.found);
+        assert_eq!(
+            network.listed,
+            [IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 3])]
+        );
+        assert_eq!(
+            network.answers,
+            [IpAddr::from([127, 0, 0, 2]), IpAddr::from([127, 0, 0, 3])]
+        );
+        assert_eq!(network.labels, ["SPAM", "VIRUS"]);
+    }
+
+    #[test]
+    fn a_network_fails_if_nothing_is_listed_and_a_lookup_failed() {
+        let mock = Mock::default().failing("192.0.2.2", "bl.test");
+        let lists = lists(&["bl.test"]);
+        let lookups = checker(mock, 8).check_lists(&[Query::from("192.0.2.0/30")], &lists);
+        assert!(matches!(lookups[0].result, Err(DnsCheckError::Timeout(_))));
+    }
+
+    #[test]
+    fn a_network_too_large_to_check_fails_without_any_lookups() {
+        let mock = Mock::default();
+        let asked = Arc::clone(&mock.asked);
+        let lists = lists(&["bl.test"]);
+        let lookups = checker(mock, 8).check_lists(&[Query::from("10.0.0.0/16")], &lists);
+        assert!(matches!(lookups[0].result, Err(DnsCheckError::TooLarge(_))));
+        assert!(asked.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn combining_keeps_the_shortest_ttl_and_ignores_skipped_lookups() {
+        let list = ListDefinition::new("bl.test");
+        let network = Query::from("192.0.2.0/31");
+        let address = |ip: &str, answers: Vec<IpAddr>, ttl| {
+            let query = Query::from(ip);
+            Ok(membership(&list, &query, answers, ttl))
+        };
+        let skipped = Ok(DnsListMembership {
+            skipped: true,
+            ..membership(&list, &Query::from("192.0.2.1"), vec![], None)
+        });
+
+        let results = vec![
+            address("192.0.2.0", vec![], Some(Duration::from_secs(300))),
+            address("192.0.2.1", vec![], Some(Duration::from_secs(60))),
+        ];
+        let combined = combine(&network, &list, results.into_iter()).unwrap();
+        assert!(!combined.found && !combined.skipped);
+        assert_eq!(combined.ttl, Some(Duration::from_secs(60)));
+
+        // We can't say how long it's good for if we don't know for every address
+        let results = vec![
+            address("192.0.2.0", vec![], Some(Duration::from_secs(300))),
+            address("192.0.2.1", vec![], None),
+        ];
+        let combined = combine(&network, &list, results.into_iter()).unwrap();
+        assert_eq!(combined.ttl, None);
+
+        let results = vec![
+            address(
+                "192.0.2.0",
+                vec![[127, 0, 0, 2].into()],
+                Some(Duration::ZERO),
+            ),
+            skipped,
+        ];
+        let combined = combine(&network, &list, results.into_iter()).unwrap();
+        assert!(combined.found);
+        assert_eq!(combined.listed, [IpAddr::from([192, 0, 2, 0])]);
+
+        let all_skipped = (0..2).map(|_| {
+            Ok(DnsListMembership {
+                skipped: true,
+                ..membership(&list, &network, vec![], None)
+            })
+        });
+        assert!(combine(&network, &list, all_skipped).unwrap().skipped);
+    }
+}
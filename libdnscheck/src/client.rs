@@ -0,0 +1,160 @@
+//! A minimal stub resolver, which talks directly to nameservers over UDP and TCP.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::wire::{build_query, parse_message, Message};
+use crate::DnsCheckError;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// The nameservers configured in `/etc/resolv.conf`, or the local host if there aren't any.
+pub fn system_nameservers() -> Vec<SocketAddr> {
+    let servers: Vec<SocketAddr> = fs::read_to_string(RESOLV_CONF)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
+
+    if servers.is_empty() {
+        vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53)]
+    } else {
+        servers
+    }
+}
+
+/// Parses a nameserver address, which may leave out the port if it's the usual one.
+pub fn parse_nameserver(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid nameserver address {:?}", s))
+}
+
+/// Sends a query to each nameserver in turn, returning the first answer we get back.
+///
+/// We ask over UDP first, and only fall back to TCP if the answer didn't fit in a datagram.
+/// However many nameservers and retries that takes, we give up at the deadline.
+pub fn query(
+    nameservers: &[SocketAddr],
+    name: &str,
+    rtype: u16,
+    deadline: Instant,
+) -> Result<Message, DnsCheckError> {
+    let mut last_error = DnsCheckError::Malformed("no nameservers to query".to_string());
+    for nameserver in nameservers {
+        let result = match query_udp(*nameserver, name, rtype, deadline) {
+            Ok(message) if message.truncated => query_tcp(*nameserver, name, rtype, deadline),
+            r => r,
+        };
+        match result {
+            Ok(message) => return Ok(message),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn query_udp(
+    nameserver: SocketAddr,
+    name: &str,
+    rtype: u16,
+    deadline: Instant,
+) -> Result<Message, DnsCheckError> {
+    let id = query_id();
+    let request = build_query(id, name, rtype)?;
+
+    let local: IpAddr = match nameserver {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))?;
+    socket.connect(nameserver)?;
+    socket.send(&request)?;
+
+    let mut buf = [0; 4096];
+    loop {
+        // A stray packet mustn't give the real answer any longer to arrive
+        socket.set_read_timeout(Some(remaining(deadline, name)?))?;
+        let len = socket.recv(&mut buf).map_err(|e| timed_out(e, name))?;
+        // Anything else is a stray answer to somebody else's question, or a forgery
+        match parse_message(&buf[..len]) {
+            Ok(message) if message.answers_query(id, name, rtype) => return Ok(message),
+            _ => {}
+        }
+    }
+}
+
+fn query_tcp(
+    nameserver: SocketAddr,
+    name: &str,
+    rtype: u16,
+    deadline: Instant,
+) -> Result<Message, DnsCheckError> {
+    let id = query_id();
+    let request = build_query(id, name, rtype)?;
+    let len: u16 = request
+        .len()
+        .try_into()
+        .map_err(|_| DnsCheckError::Malformed(format!("query for {} is too long", name)))?;
+
+    let mut stream = TcpStream::connect_timeout(&nameserver, remaining(deadline, name)?)
+        .map_err(|e| timed_out(e, name))?;
+    stream.set_write_timeout(Some(remaining(deadline, name)?))?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&request)?;
+
+    // Over TCP, every message is prefixed with its length
+    let mut len = [0; 2];
+    stream.set_read_timeout(Some(remaining(deadline, name)?))?;
+    stream
+        .read_exact(&mut len)
+        .map_err(|e| timed_out(e, name))?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.set_read_timeout(Some(remaining(deadline, name)?))?;
+    stream
+        .read_exact(&mut buf)
+        .map_err(|e| timed_out(e, name))?;
+    let message = parse_message(&buf)?;
+    if !message.answers_query(id, name, rtype) {
+        return Err(DnsCheckError::Malformed(format!(
+            "answer from {} doesn't match our query for {}",
+            nameserver, name
+        )));
+    }
+    Ok(message)
+}
+
+/// How long we have left, which must be something: a zero timeout means no timeout at all.
+fn remaining(deadline: Instant, name: &str) -> Result<Duration, DnsCheckError> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if left > Duration::ZERO => Ok(left),
+        _ => Err(DnsCheckError::Timeout(name.to_string())),
+    }
+}
+
+fn timed_out(e: io::Error, name: &str) -> DnsCheckError {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            DnsCheckError::Timeout(name.to_string())
+        }
+        _ => e.into(),
+    }
+}
+
+/// A query ID nobody else can guess, from the random keys the standard library seeds its hash
+/// maps with.
+fn query_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
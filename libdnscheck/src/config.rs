@@ -0,0 +1,547 @@
+//! Settings shared by every tool: which lists to check, and how to read their answers.
+//!
+//! We read the system-wide file first, then the user's own file, with anything the user sets
+//! taking precedence.  A file given on the command line replaces both.
+//!
+//! ```toml
+//! [defaults]
+//! timeout = 10
+//! family = "a"
+//! cache_ttl = 60
+//!
+//! [lists.spamhaus]
+//! zone = "zen.spamhaus.org"
+//! role = "block"
+//! query = "ip"
+//! weight = 3.0
+//! codes = { "2" = "SBL", "3" = "CSS", "4-7" = "XBL", "10-11" = "PBL" }
+//! sentinels = { "127.255.255.252" = "typing error", "127.255.255.254-255" = "refused" }
+//!
+//! [lists.dnswl]
+//! zone = "list.dnswl.org"
+//! role = "allow"
+//!
+//! [sets]
+//! default = ["dnswl", "spamhaus"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    Cache, Checker, DnsCheckError, Family, Limits, ListDefinition, NameserverResolver, Output,
+    QueryType, Resolver, ResolverKind, ReturnCode, Sentinel, DEFAULT_IPV6_PREFIX,
+};
+
+const SYSTEM_CONFIG: &str = "/etc/dnscheck/config.toml";
+
+/// Whether a list tells us who to let in or who to keep out.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Allow,
+    #[default]
+    Block,
+}
+
+/// Settings for anything that isn't given on the command line.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    pub jobs: Option<usize>,
+    pub timeout: Option<u64>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub family: Option<Family>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub resolver: Option<ResolverKind>,
+    /// The score at which we'll consider a query blocked.
+    pub threshold: Option<f64>,
+    /// Seconds to remember answers from resolvers that don't tell us their TTL.
+    pub cache_ttl: Option<u64>,
+    /// Whether to remember answers at all, which we do unless told not to.
+    pub cache: Option<bool>,
+}
+
+impl Defaults {
+    /// Our usual limits, unless the configuration overrides them.
+    pub fn limits(&self) -> Limits {
+        let limits = Limits::default();
+        Limits {
+            parallelism: self.jobs.unwrap_or(limits.parallelism),
+            timeout: self.timeout.map_or(limits.timeout, Duration::from_secs),
+        }
+    }
+
+    fn merge(&mut self, other: Defaults) {
+        self.jobs = other.jobs.or(self.jobs);
+        self.timeout = other.timeout.or(self.timeout);
+        self.family = other.family.or(self.family);
+        self.resolver = other.resolver.or(self.resolver);
+        self.threshold = other.threshold.or(self.threshold);
+        self.cache_ttl = other.cache_ttl.or(self.cache_ttl);
+        self.cache = other.cache.or(self.cache);
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListConfig {
+    /// The list's DNS zone, if it's not the same as the name we've given it.
+    zone: Option<String>,
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    query: QueryType,
+    #[serde(default = "default_weight")]
+    weight: f64,
+    /// Labels for the answers that count as listings, keyed by answer.
+    #[serde(default)]
+    codes: BTreeMap<String, String>,
+    /// Answers that mean the list refused the query, with their reasons, instead of the ones its
+    /// operator documents.
+    sentinels: Option<BTreeMap<String, String>>,
+    /// The size of the IPv6 networks the list treats as one, if it's not a /64.
+    ipv6_prefix: Option<u8>,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(default)]
+    lists: BTreeMap<String, ListConfig>,
+    #[serde(default)]
+    sets: BTreeMap<String, Vec<String>>,
+}
+
+/// What a tool's command line says about how to look things up, which takes precedence over the
+/// configuration's defaults.
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    pub jobs: Option<usize>,
+    pub timeout: Option<u64>,
+    pub family: Option<Family>,
+    pub resolver: Option<ResolverKind>,
+    /// Nameservers to send queries to directly, instead of using any resolver.
+    pub nameservers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    pub defaults: Defaults,
+    lists: BTreeMap<String, ListConfig>,
+    sets: BTreeMap<String, Vec<String>>,
+}
+
+impl Config {
+    /// Reads the given file, or the system and user files if we're not given one.
+    ///
+    /// It's not an error for the system or user file to be missing, but it is for one we've been
+    /// given explicitly.
+    pub fn load(path: Option<&Path>) -> Result<Config, DnsCheckError> {
+        let paths = [Some(PathBuf::from(SYSTEM_CONFIG)), user_config()];
+        Config::load_from(path, paths.iter().flatten())
+    }
+
+    fn load_from<'a>(
+        path: Option<&Path>,
+        usual: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> Result<Config, DnsCheckError> {
+        let mut config = Config::default();
+        match path {
+            Some(path) => config.merge(read(path)?),
+            None => {
+                for path in usual {
+                    if path.exists() {
+                        config.merge(read(path)?);
+                    }
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, file: ConfigFile) {
+        self.defaults.merge(file.defaults);
+        self.lists.extend(file.lists);
+        self.sets.extend(file.sets);
+    }
+
+    pub fn has_set(&self, name: &str) -> bool {
+        self.sets.contains_key(name)
+    }
+
+    /// The lists in a named set with the given role.
+    pub fn set(&self, name: &str, role: Role) -> Result<Vec<ListDefinition>, DnsCheckError> {
+        let set = self
+            .sets
+            .get(name)
+            .ok_or_else(|| DnsCheckError::Config(format!("no list set called {:?}", name)))?;
+
+        let mut lists = vec![];
+        for list in set {
+            let config = self.lists.get(list).ok_or_else(|| {
+                DnsCheckError::Config(format!("set {:?} uses unknown list {:?}", name, list))
+            })?;
+            if config.role == role {
+                lists.push(definition(list, config)?);
+            }
+        }
+        Ok(lists)
+    }
+
+    /// A checker that looks things up the way the command line says, or the configuration
+    /// otherwise, remembering the answers it gets unless the configuration sets `cache = false`.
+    pub fn checker(&self, output: Output, overrides: Overrides) -> Result<Checker, DnsCheckError> {
+        let defaults = &self.defaults;
+        let default_limits = defaults.limits();
+        let limits = Limits {
+            parallelism: overrides.jobs.unwrap_or(default_limits.parallelism),
+            timeout: overrides
+                .timeout
+                .map_or(default_limits.timeout, Duration::from_secs),
+        };
+        check_limits(&limits).map_err(DnsCheckError::Config)?;
+        let resolver: Box<dyn Resolver> = if overrides.nameservers.is_empty() {
+            let kind = overrides
+                .resolver
+                .or(defaults.resolver)
+                .unwrap_or(ResolverKind::Auto);
+            kind.connect(output, limits.timeout)?
+        } else {
+            Box::new(NameserverResolver::new(
+                overrides.nameservers,
+                limits.timeout,
+            ))
+        };
+        let family = overrides.family.or(defaults.family).unwrap_or_default();
+        let checker = Checker::with_resolver(output, limits, resolver).with_family(family);
+        Ok(match self.cache() {
+            Some(cache) => checker.with_cache(cache),
+            None => checker,
+        })
+    }
+
+    fn cache(&self) -> Option<Cache> {
+        let defaults = &self.defaults;
+        if defaults.cache == Some(false) {
+            return None;
+        }
+        Some(match defaults.cache_ttl {
+            Some(ttl) => Cache::default().with_default_ttl(Duration::from_secs(ttl)),
+            None => Cache::default(),
+        })
+    }
+
+    /// The allow and block lists a tool should check: the ones it was given, plus those in the
+    /// named sets, or in the `default` set if it wasn't given any lists at all.
+    pub fn lists(
+        &self,
+        sets: &[String],
+        allow: &[ListDefinition],
+        block: &[ListDefinition],
+    ) -> Result<(Vec<ListDefinition>, Vec<ListDefinition>), DnsCheckError> {
+        let mut sets = sets.to_vec();
+        if sets.is_empty() && allow.is_empty() && block.is_empty() && self.has_set("default") {
+            sets.push("default".to_string());
+        }
+        let mut allow = allow.to_vec();
+        let mut block = block.to_vec();
+        for set in &sets {
+            allow.extend(self.set(set, Role::Allow)?);
+            block.extend(self.set(set, Role::Block)?);
+        }
+        Ok((allow, block))
+    }
+}
+
+fn definition(name: &str, config: &ListConfig) -> Result<ListDefinition, DnsCheckError> {
+    let codes = config
+        .codes
+        .iter()
+        .map(|(codes, label)| ReturnCode::parse(codes, label))
+        .collect::<Result<_, _>>()
+        .map_err(|e| DnsCheckError::Config(format!("list {:?}: {}", name, e)))?;
+    let sentinels = match &config.sentinels {
+        Some(sentinels) => Some(
+            sentinels
+                .iter()
+                .map(|(codes, reason)| Sentinel::parse(codes, reason))
+                .collect::<Result<_, _>>()
+                .map_err(|e| DnsCheckError::Config(format!("list {:?}: {}", name, e)))?,
+        ),
+        None => None,
+    };
+    let ipv6_prefix = config.ipv6_prefix.unwrap_or(DEFAULT_IPV6_PREFIX);
+    if ipv6_prefix > 128 {
+        return Err(DnsCheckError::Config(format!(
+            "list {:?}: invalid ipv6_prefix {}",
+            name, ipv6_prefix
+        )));
+    }
+
+    let list = ListDefinition::new(config.zone.as_deref().unwrap_or(name));
+    Ok(ListDefinition {
+        codes,
+        sentinels: sentinels.unwrap_or(list.sentinels),
+        query: config.query,
+        weight: config.weight,
+        ipv6_prefix,
+        ..list
+    })
+}
+
+fn read(path: &Path) -> Result<ConfigFile, DnsCheckError> {
+    let contents = fs::read_to_string(path).map_err(|e| invalid(path, e))?;
+    parse(&contents).map_err(|e| invalid(path, e))
+}
+
+fn parse(contents: &str) -> Result<ConfigFile, String> {
+    let file: ConfigFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+    check_limits(&file.defaults.limits())?;
+    Ok(file)
+}
+
+/// We'd never finish with no lookups at a time, and never wait for one with no time at all.
+fn check_limits(limits: &Limits) -> Result<(), String> {
+    if limits.parallelism == 0 {
+        Err("jobs must be at least 1".to_string())
+    } else if limits.timeout == Duration::ZERO {
+        Err("timeout must be at least 1 second".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn invalid(path: &Path, e: impl Display) -> DnsCheckError {
+    DnsCheckError::Config(format!("reading {}: {}", path.display(), e))
+}
+
+/// Where the user's own settings live, following the XDG base directory conventions.
+fn user_config() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("dnscheck").join("config.toml"))
+}
+
+/// Deserialises anything we'd otherwise parse from the command line.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(D::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(files: &[&str]) -> Config {
+        let mut config = Config::default();
+        for file in files {
+            config.merge(parse(file).unwrap());
+        }
+        config
+    }
+
+    fn names(lists: &[ListDefinition]) -> Vec<&str> {
+        lists.iter().map(|l| l.name.as_str()).collect()
+    }
+
+    /// A file of our own in the temporary directory, removed when we're done with it.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let file = format!("dnscheck-config-{}-{}.toml", std::process::id(), name);
+            let path = std::env::temp_dir().join(file);
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const SYSTEM: &str = r#"
+        [defaults]
+        timeout = 10
+        jobs = 4
+
+        [lists.spamhaus]
+        zone = "zen.spamhaus.org"
+        weight = 3.0
+        codes = { "2" = "SBL", "4-7" = "XBL" }
+
+        [lists.dnswl]
+        zone = "list.dnswl.org"
+        role = "allow"
+
+        [sets]
+        default = ["dnswl", "spamhaus"]
+    "#;
+
+    const USER: &str = r#"
+        [defaults]
+        timeout = 5
+
+        [lists.surbl]
+        zone = "multi.surbl.org"
+        query = "domain"
+
+        [sets]
+        default = ["surbl"]
+        mail = ["dnswl", "spamhaus", "surbl"]
+    "#;
+
+    #[test]
+    fn lists_are_read_with_their_settings() {
+        let config = config(&[SYSTEM]);
+        let block = config.set("default", Role::Block).unwrap();
+        assert_eq!(names(&block), vec!["zen.spamhaus.org"]);
+        assert_eq!(block[0].weight, 3.0);
+        assert_eq!(block[0].codes.len(), 2);
+        assert_eq!(block[0].ipv6_prefix, DEFAULT_IPV6_PREFIX);
+        let allow = config.set("default", Role::Allow).unwrap();
+        assert_eq!(names(&allow), vec!["list.dnswl.org"]);
+        assert_eq!(allow[0].weight, 1.0);
+    }
+
+    #[test]
+    fn the_users_settings_take_precedence() {
+        let config = config(&[SYSTEM, USER]);
+        assert_eq!(config.defaults.timeout, Some(5));
+        assert_eq!(config.defaults.jobs, Some(4));
+        assert_eq!(config.set("default", Role::Allow).unwrap(), vec![]);
+        assert_eq!(
+            names(&config.set("default", Role::Block).unwrap()),
+            vec!["multi.surbl.org"]
+        );
+
+        // The user's sets can use the system's lists
+        let (allow, block) = config.lists(&["mail".to_string()], &[], &[]).unwrap();
+        assert_eq!(names(&allow), vec!["list.dnswl.org"]);
+        assert_eq!(names(&block), vec!["zen.spamhaus.org", "multi.surbl.org"]);
+    }
+
+    #[test]
+    fn a_file_we_are_given_replaces_the_usual_ones() {
+        let system = TempFile::new("system", SYSTEM);
+        let user = TempFile::new("user", USER);
+        let given = TempFile::new("given", "[defaults]\njobs = 2\n");
+        let usual = [system.0.clone(), user.0.clone()];
+
+        let config = Config::load_from(None, &usual).unwrap();
+        assert_eq!(config.defaults.timeout, Some(5));
+        assert!(config.has_set("mail"));
+
+        let config = Config::load_from(Some(&given.0), &usual).unwrap();
+        assert_eq!(config.defaults.jobs, Some(2));
+        assert_eq!(config.defaults.timeout, None);
+        assert!(!config.has_set("default"));
+    }
+
+    #[test]
+    fn only_a_file_we_are_given_has_to_exist() {
+        let missing = std::env::temp_dir().join("dnscheck-config-missing.toml");
+        let config = Config::load_from(None, Some(&missing)).unwrap();
+        assert!(!config.has_set("default"));
+        assert!(Config::load_from(Some(&missing), None).is_err());
+    }
+
+    #[test]
+    fn lists_filter_sets_by_role_and_keep_the_ones_we_were_given() {
+        let config = config(&[SYSTEM]);
+        let given: ListDefinition = "bl.example".parse().unwrap();
+
+        // The default set is only for when we're not given any lists
+        let (allow, block) = config
+            .lists(&[], &[], std::slice::from_ref(&given))
+            .unwrap();
+        assert_eq!(names(&allow), Vec::<&str>::new());
+        assert_eq!(names(&block), vec!["bl.example"]);
+
+        let (allow, block) = config.lists(&[], &[], &[]).unwrap();
+        assert_eq!(names(&allow), vec!["list.dnswl.org"]);
+        assert_eq!(names(&block), vec!["zen.spamhaus.org"]);
+    }
+
+    #[test]
+    fn lists_keep_their_query_type() {
+        let config = config(&[SYSTEM, USER]);
+        let block = config.set("mail", Role::Block).unwrap();
+        assert_eq!(block[0].query, QueryType::default());
+        assert_eq!(block[1].query, QueryType::Domain);
+    }
+
+    #[test]
+    fn unknown_sets_and_lists_are_errors() {
+        let config = config(&[SYSTEM, "[sets]\nbroken = [\"spamhaus\", \"nowhere\"]\n"]);
+        let error = config.set("broken", Role::Block).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid configuration: set \"broken\" uses unknown list \"nowhere\""
+        );
+        let error = config
+            .lists(&["missing".to_string()], &[], &[])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid configuration: no list set called \"missing\""
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        assert!(parse("[defaults]\ntimeuot = 5\n").is_err());
+        assert!(parse("[lists.spamhaus]\nrole = \"deny\"\n").is_err());
+        assert!(parse("[defaults]\nresolver = \"carrier pigeon\"\n").is_err());
+    }
+
+    #[test]
+    fn limits_must_let_us_look_things_up() {
+        assert_eq!(
+            parse("[defaults]\njobs = 0\n").unwrap_err(),
+            "jobs must be at least 1"
+        );
+        assert_eq!(
+            parse("[defaults]\ntimeout = 0\n").unwrap_err(),
+            "timeout must be at least 1 second"
+        );
+        assert!(parse("[defaults]\njobs = 1\ntimeout = 1\n").is_ok());
+    }
+
+    #[test]
+    fn caching_can_be_turned_off() {
+        assert!(config(&[]).cache().is_some());
+        assert!(config(&["[defaults]\ncache_ttl = 60\n"]).cache().is_some());
+        assert!(config(&["[defaults]\ncache = false\n"]).cache().is_none());
+        assert!(
+            config(&["[defaults]\ncache = false\n", "[defaults]\ncache = true\n"])
+                .cache()
+                .is_some()
+        );
+    }
+}
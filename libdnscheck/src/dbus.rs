@@ -1,17 +1,25 @@
-use dbus::blocking::Connection;
+use dbus::blocking::{Proxy, SyncConnection};
 use dbus::{Error as DBusError, MethodErr};
 
-use generate_dbus_resolve1::OrgFreedesktopResolve1Manager;
+use generate_dbus_resolve1::{OrgFreedesktopDBusPeer, OrgFreedesktopResolve1Manager};
 
-use crate::{format_ip, DnsCheckError, DnsListMembership, Output, Query};
+use crate::wire::{Record, CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_TXT};
+use crate::{wire, Answer, DnsCheckError, Family, Resolver};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 
+const NO_SUCH_RR: &str = "org.freedesktop.resolve1.NoSuchRR";
+
 impl From<MethodErr> for DnsCheckError {
     fn from(e: MethodErr) -> Self {
         if e.errorname()
             .starts_with("org.freedesktop.resolve1.DnsError.NXDOMAIN")
         {
             DnsCheckError::NxDomain(e.description().to_string())
+        } else if &**e.errorname() == "org.freedesktop.DBus.Error.NoReply" {
+            DnsCheckError::Timeout(e.description().to_string())
         } else {
             DnsCheckError::DBus(e.errorname().to_string(), e.description().to_string())
         }
@@ -24,57 +32,90 @@ impl From<DBusError> for DnsCheckError {
     }
 }
 
-pub fn lookup_dbus(
-    source: &str,
-    query: &Query,
-    output: &Output,
-) -> Result<DnsListMembership, DnsCheckError> {
-    if output == &Output::Verbose {
-        println!("Source: {:?}, Query: {:?}", source, query);
-    }
+/// Resolves names by asking systemd-resolved over D-Bus, sharing one connection between every
+/// lookup.
+pub struct DBusResolver {
+    proxy: Proxy<'static, Arc<SyncConnection>>,
+}
 
-    let conn = Connection::new_system().map_err(|e| DnsCheckError::NoResolved(e.into()))?;
-    let proxy = conn.with_proxy(
-        "org.freedesktop.resolve1",
-        "/org/freedesktop/resolve1",
-        Duration::from_secs(30),
-    );
+impl DBusResolver {
+    /// Opens the system bus and checks that systemd-resolved is there to answer us.
+    pub fn connect(timeout: Duration) -> Result<DBusResolver, DnsCheckError> {
+        let conn = SyncConnection::new_system().map_err(|e| DnsCheckError::NoResolved(e.into()))?;
+        let proxy = Proxy::new(
+            "org.freedesktop.resolve1",
+            "/org/freedesktop/resolve1",
+            timeout,
+            Arc::new(conn),
+        );
+        proxy
+            .ping()
+            .map_err(|e| DnsCheckError::NoResolved(e.into()))?;
+        Ok(DBusResolver { proxy })
+    }
 
-    let queryhost = match query {
-        Query::Domain(d) => format!("{}.", d),
-        Query::Address(ip) => format_ip(&ip),
-    };
+    /// Returns every record of the given type, or nothing if the name doesn't exist.
+    fn resolve_record(&self, hostname: &str, rtype: u16) -> Result<Vec<Record>, DnsCheckError> {
+        type DBusRecordResponse = (Vec<(i32, u16, u16, Vec<u8>)>, u64);
+        let result: Result<DBusRecordResponse, DnsCheckError> = self
+            .proxy
+            .resolve_record(0, hostname, CLASS_IN, rtype, 0)
+            .map_err(From::from);
 
-    let hostname = format!("{}{}.", queryhost, source);
+        match result {
+            Ok((records, _)) => records
+                .iter()
+                .filter(|(_, _, t, _)| *t == rtype)
+                // resolved gives us each record in full, including its name, class and TTL
+                .map(|(_, _, _, record)| wire::parse_record(record))
+                .collect(),
+            Err(DnsCheckError::NxDomain(_)) => Ok(vec![]),
+            // The name exists, but not with a record of the type we asked for
+            Err(DnsCheckError::DBus(name, _)) if name == NO_SUCH_RR => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+}
 
-    if output == &Output::Verbose {
-        println!("Querying: {}", hostname);
+impl Resolver for DBusResolver {
+    fn resolve(&self, hostname: &str, family: Family) -> Result<Vec<IpAddr>, DnsCheckError> {
+        self.resolve_answer(hostname, family)
+            .map(|answer| answer.addresses)
     }
 
-    type DBusDnsResponse = (Vec<(i32, i32, Vec<u8>)>, String, u64);
-    let result: Result<DBusDnsResponse, DnsCheckError> = proxy
-        .resolve_hostname(0, &hostname, libc::AF_INET, 0)
-        .map_err(From::from);
+    /// We ask for the records themselves rather than using `ResolveHostname`, as that doesn't
+    /// tell us their TTL.  resolved doesn't pass on the SOA record for a name that doesn't exist,
+    /// so we can't say how long an empty answer is good for.
+    fn resolve_answer(&self, hostname: &str, family: Family) -> Result<Answer, DnsCheckError> {
+        let mut records = vec![];
+        if family != Family::Ipv6 {
+            records.extend(self.resolve_record(hostname, TYPE_A)?);
+        }
+        if family != Family::Ipv4 {
+            records.extend(self.resolve_record(hostname, TYPE_AAAA)?);
+        }
+        let addresses = records
+            .iter()
+            .filter_map(|record| to_ip(&record.data))
+            .collect();
+        let ttl = records
+            .iter()
+            .map(|record| Duration::from_secs(record.ttl.into()))
+            .min();
+        Ok(Answer { addresses, ttl })
+    }
 
-    if output == &Output::Verbose {
-        println!("Result: {:?}", result);
+    fn resolve_txt(&self, hostname: &str) -> Result<Vec<String>, DnsCheckError> {
+        self.resolve_record(hostname, TYPE_TXT)?
+            .iter()
+            .map(|record| wire::txt_data(&record.data))
+            .collect()
     }
+}
 
-    result.map_or_else(
-        |error| match error {
-            DnsCheckError::NxDomain(_) => Ok(DnsListMembership {
-                name: format!("{}", query),
-                list: source.to_string(),
-                found: false,
-            }),
-            e => Err(e),
-        },
-        |r| {
-            Ok(DnsListMembership {
-                name: format!("{}", query),
-                list: source.to_string(),
-                found: !r.0.is_empty(),
-            })
-        },
-    )
+fn to_ip(data: &[u8]) -> Option<IpAddr> {
+    <[u8; 4]>::try_from(data)
+        .map(IpAddr::from)
+        .or_else(|_| <[u8; 16]>::try_from(data).map(IpAddr::from))
+        .ok()
 }
@@ -0,0 +1,59 @@
+//! Runs lookups concurrently, so one slow list doesn't hold up all the others.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::DEFAULT_TIMEOUT;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Limits {
+    /// The most lookups we'll have in flight at any one time.
+    pub parallelism: usize,
+    /// How long we'll wait for any single lookup before giving up on it.
+    pub timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            parallelism: 8,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// Runs `job` over every item, with at most `parallelism` running at once.
+///
+/// Results come back in the same order as the items they were produced from.
+pub fn run<T, R, F>(items: &[T], parallelism: usize, job: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..parallelism.clamp(1, items.len().max(1)) {
+            let tx = tx.clone();
+            let next = &next;
+            let job = &job;
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let item = match items.get(index) {
+                    Some(item) => item,
+                    None => break,
+                };
+                // The receiver outlives every worker, so this can't fail
+                let _ = tx.send((index, job(item)));
+            });
+        }
+    });
+    drop(tx);
+
+    let mut results: Vec<_> = rx.into_iter().collect();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
@@ -1,20 +1,60 @@
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, Ipv6Addr};
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
 use std::{fmt, io};
 
-use dns_lookup::{getaddrinfo, LookupErrorKind};
 use thiserror::Error;
 
-use crate::dbus::lookup_dbus;
-use crate::DnsCheckError::{NoDBus, NoResolved};
+pub use crate::cache::Cache;
+pub use crate::checker::{Checker, Lookup, StopAfter};
+pub use crate::client::parse_nameserver;
+pub use crate::config::{Config, Defaults, Overrides, Role};
+pub use crate::dbus::DBusResolver;
+pub use crate::executor::Limits;
+pub use crate::list::{ListDefinition, QueryType, ReturnCode};
+pub use crate::mail::{helo_domain, sender_domain, Decision};
+pub use crate::nameserver::NameserverResolver;
+pub use crate::network::{DEFAULT_IPV6_PREFIX, MAX_NETWORK_LOOKUPS};
+pub use crate::report::{ExitStatus, Format, ListReport, QueryReport, Tally};
+pub use crate::resolver::{Answer, AutoResolver, Resolver, ResolverKind};
+pub use crate::sentinel::{default_sentinels, Sentinel};
+#[cfg(unix)]
+pub use crate::server::{Connection, Listen};
+pub use crate::system::SystemResolver;
+pub use crate::zone::{Listing, Zone, ZoneFormat};
 
+mod cache;
+mod checker;
+mod client;
+mod config;
 #[cfg(all(feature = "dbus", target_os = "linux"))]
 mod dbus;
+mod executor;
+mod list;
+mod mail;
+mod nameserver;
+mod network;
+mod report;
+mod resolver;
+mod sentinel;
+#[cfg(unix)]
+mod server;
+mod system;
+mod wire;
+mod zone;
+
+/// How long we'll wait for an answer if we're not told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Copy, Clone)]
 pub enum Query<'a> {
     Address(IpAddr),
     Domain(&'a str),
+    /// A network, as its first address and prefix length, checked an address at a time.
+    Network(IpAddr, u8),
 }
 
 impl Display for Query<'_> {
@@ -26,10 +66,62 @@ impl Display for Query<'_> {
             Query::Domain(domain) => {
                 write!(f, "{}", domain)
             }
+            Query::Network(network, prefix) => {
+                write!(f, "{}/{}", network, prefix)
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Query<'a> {
+    fn from(query: &'a str) -> Self {
+        if let Ok(ip) = IpAddr::from_str(query) {
+            return Query::Address(ip);
+        }
+        match network::parse_network(query) {
+            Some((ip @ IpAddr::V4(_), 32)) | Some((ip @ IpAddr::V6(_), 128)) => Query::Address(ip),
+            Some((network, prefix)) => Query::Network(network, prefix),
+            None => Query::Domain(query),
+        }
+    }
+}
+
+impl<'a> Query<'a> {
+    /// Reads a query like `From` does, but turns down a network that's too large for any of the
+    /// lists that would check it, so we can say so before looking anything up.
+    pub fn parse<'l>(
+        query: &'a str,
+        lists: impl IntoIterator<Item = &'l ListDefinition>,
+    ) -> Result<Query<'a>, DnsCheckError> {
+        let query = Query::from(query);
+        if let Query::Network(network, prefix) = query {
+            let too_large = lists.into_iter().any(|list| {
+                list.query.accepts(&query)
+                    && network::addresses(network, prefix, list.ipv6_prefix).is_none()
+            });
+            if too_large {
+                return Err(DnsCheckError::TooLarge(query.to_string()));
+            }
         }
+        Ok(query)
     }
 }
 
+/// Reads queries one per line, skipping blank lines and `#` comments.
+pub fn read_queries<R: BufRead>(input: R) -> impl Iterator<Item = io::Result<String>> {
+    input.lines().filter_map(|line| match line {
+        Ok(line) => {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                None
+            } else {
+                Some(Ok(line.to_string()))
+            }
+        }
+        Err(e) => Some(Err(e)),
+    })
+}
+
 #[derive(Error, Debug)]
 pub enum DnsCheckError {
     #[error("DBus reported {0}: {1}")]
@@ -40,20 +132,122 @@ pub enum DnsCheckError {
     NoResolved(#[source] anyhow::Error),
     #[error("NXDOMAIN {0}")]
     NxDomain(String),
+    #[error("Malformed DNS response: {0}")]
+    Malformed(String),
+    #[error("Timed out looking up {0}")]
+    Timeout(String),
+    #[error("Nameserver returned error code {1} looking up {0}")]
+    Nameserver(String, u8),
+    #[error("{list} refused to answer with {answer}: {reason}")]
+    Refused {
+        list: String,
+        answer: IpAddr,
+        reason: String,
+    },
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("{0} has too many addresses to check")]
+    TooLarge(String),
     #[error("Something went wrong: {0}")]
     Unknown(#[from] anyhow::Error),
 }
 
+impl DnsCheckError {
+    /// A short, stable name for the kind of error, for tools that want to tell them apart.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DnsCheckError::DBus(_, _) => "dbus",
+            DnsCheckError::NoDBus => "no-dbus",
+            DnsCheckError::NoResolved(_) => "no-resolved",
+            DnsCheckError::NxDomain(_) => "nxdomain",
+            DnsCheckError::Malformed(_) => "malformed",
+            DnsCheckError::Timeout(_) => "timeout",
+            DnsCheckError::Nameserver(_, _) => "nameserver",
+            DnsCheckError::Refused { .. } => "refused",
+            DnsCheckError::Config(_) => "config",
+            DnsCheckError::TooLarge(_) => "too-large",
+            DnsCheckError::Unknown(_) => "unknown",
+        }
+    }
+}
+
 impl From<io::Error> for DnsCheckError {
     fn from(e: io::Error) -> Self {
         DnsCheckError::Unknown(e.into())
     }
 }
 
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DnsListMembership {
+    #[cfg_attr(feature = "serde", serde(rename = "query"))]
     pub name: String,
     pub list: String,
     pub found: bool,
+    /// Whether we didn't look the query up at all, because we'd already found what we needed.
+    pub skipped: bool,
+    /// Every address the list returned: most lists encode the reason for a listing in these,
+    /// usually as `127.0.0.x`.
+    pub answers: Vec<IpAddr>,
+    /// The labels of the list's return codes that matched, if its definition gave any.
+    pub labels: Vec<String>,
+    /// The list's weight if the query is listed, otherwise nothing.
+    pub score: f64,
+    /// How much longer the list's answer is good for, if the resolver told us.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "crate::report::optional_seconds")
+    )]
+    pub ttl: Option<Duration>,
+    /// For a network, the addresses in it that the list has.
+    pub listed: Vec<IpAddr>,
+}
+
+/// Which kinds of address record we'll accept as answers from a list.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Family {
+    /// Only A records, which is what almost every list publishes.
+    #[default]
+    Ipv4,
+    /// Only AAAA records.
+    Ipv6,
+    /// Both A and AAAA records.
+    Both,
+}
+
+impl Family {
+    /// The address family to ask the system resolver for.
+    fn af(self) -> i32 {
+        match self {
+            Family::Ipv4 => libc::AF_INET,
+            Family::Ipv6 => libc::AF_INET6,
+            Family::Both => libc::AF_UNSPEC,
+        }
+    }
+
+    pub fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            Family::Ipv4 => ip.is_ipv4(),
+            Family::Ipv6 => ip.is_ipv6(),
+            Family::Both => true,
+        }
+    }
+}
+
+impl FromStr for Family {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "a" | "4" | "ipv4" => Ok(Family::Ipv4),
+            "aaaa" | "6" | "ipv6" => Ok(Family::Ipv6),
+            "both" | "any" => Ok(Family::Both),
+            _ => Err(format!(
+                "unknown address family {:?}, expected one of a, aaaa or both",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -63,70 +257,101 @@ pub enum Output {
     Verbose,
 }
 
+/// The checker behind the free functions, set up the first time it's needed for each kind of
+/// output, so every call shares one resolver rather than connecting to the bus again.  Anything
+/// that wants its own limits or a cache should keep a `Checker` of its own instead.
+fn shared_checker(output: Output) -> &'static Checker {
+    static CHECKERS: [OnceLock<Checker>; 3] = [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+    let index = match output {
+        Output::Quiet => 0,
+        Output::Normal => 1,
+        Output::Verbose => 2,
+    };
+    CHECKERS[index].get_or_init(|| Checker::new(output, Limits::default()))
+}
+
 pub fn lookup(
     source: &str,
     query: &Query,
     output: &Output,
 ) -> Result<DnsListMembership, DnsCheckError> {
-    match lookup_dbus(source, query, output) {
-        Ok(r) => return Ok(r),
-        Err(NoDBus) => {
-            if output == &Output::Verbose {
-                eprintln!("DBus not compiled in, falling back to internal resolution")
-            }
+    shared_checker(*output).lookup(&ListDefinition::from(source), query)
+}
+
+/// Fetches the TXT records a list publishes for a query, which usually explain why it's listed.
+pub fn lookup_txt(
+    source: &str,
+    query: &Query,
+    output: &Output,
+) -> Result<Vec<String>, DnsCheckError> {
+    shared_checker(*output).lookup_txt(source, query)
+}
+
+#[cfg(not(all(feature = "dbus", target_os = "linux")))]
+mod dbus {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use crate::DnsCheckError::NoDBus;
+    use crate::{DnsCheckError, Family, Resolver};
+
+    /// Stands in for the systemd-resolved resolver when D-Bus support isn't compiled in: it can
+    /// never be connected.
+    pub enum DBusResolver {}
+
+    impl DBusResolver {
+        pub fn connect(_: Duration) -> Result<DBusResolver, DnsCheckError> {
+            Err(NoDBus)
         }
-        Err(NoResolved(e)) => {
-            if output == &Output::Verbose {
-                eprintln!("DBus resolution failed: {:?}", e)
-            }
+    }
+
+    impl Resolver for DBusResolver {
+        fn resolve(&self, _: &str, _: Family) -> Result<Vec<IpAddr>, DnsCheckError> {
+            match *self {}
         }
-        Err(e) => return Err(e),
-    };
 
-    lookup_dns(source, query, output)
+        fn resolve_txt(&self, _: &str) -> Result<Vec<String>, DnsCheckError> {
+            match *self {}
+        }
+    }
 }
 
-fn lookup_dns(
-    source: &str,
-    query: &Query,
-    _output: &Output,
-) -> Result<DnsListMembership, DnsCheckError> {
+fn list_hostname(source: &str, query: &Query) -> String {
     let queryhost = match query {
         Query::Domain(d) => format!("{}.", d),
-        Query::Address(ip) => format_ip(&ip),
+        Query::Address(ip) | Query::Network(ip, _) => format_ip(ip),
     };
 
-    let hostname = format!("{}{}.", queryhost, source);
-
-    let mut addrinfo = match getaddrinfo(Some(&hostname), None, None) {
-        Ok(a) => Ok(a),
-        Err(e) => match e.kind() {
-            LookupErrorKind::NoName => {
-                return Ok(DnsListMembership {
-                    name: query.to_string(),
-                    list: format!("{:?}", source),
-                    found: false,
-                })
-            }
-            _ => Err(DnsCheckError::Unknown(io::Error::from(e).into())),
-        },
-    }?;
-
-    Ok(DnsListMembership {
-        name: source.to_string(),
-        list: format!("{}", query),
-        found: addrinfo.next().is_some(),
-    })
+    format!("{}{}.", queryhost, source)
 }
 
-#[cfg(not(all(feature = "dbus", target_os = "linux")))]
-mod dbus {
-    use crate::DnsCheckError::NoDBus;
-    use crate::{DnsCheckError, DnsListMembership, Output, Query};
-
-    pub fn lookup_dbus(_: &str, _: &Query, _: &Output) -> Result<DnsListMembership, DnsCheckError> {
-        Err(NoDBus)
+/// The query a list is being asked about, from the part of a name in front of the list's zone:
+/// the reverse of the names we look up.
+pub fn list_query(prefix: &str) -> Query<'_> {
+    let prefix = prefix.trim_end_matches('.');
+    let labels: Vec<&str> = prefix.split('.').rev().collect();
+    if labels.len() == 4 {
+        let octets: Result<Vec<u8>, _> = labels.iter().map(|l| l.parse::<u8>()).collect();
+        if let Ok(octets) = octets {
+            return Query::Address(
+                Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into(),
+            );
+        }
     }
+    if labels.len() == 32 {
+        let nibbles: Option<Vec<u8>> = labels
+            .iter()
+            .map(|l| u8::from_str_radix(l, 16).ok().filter(|_| l.len() == 1))
+            .collect();
+        if let Some(nibbles) = nibbles {
+            let mut octets = [0; 16];
+            for (octet, pair) in octets.iter_mut().zip(nibbles.chunks(2)) {
+                *octet = pair[0] << 4 | pair[1];
+            }
+            return Query::Address(Ipv6Addr::from(octets).into());
+        }
+    }
+    Query::Domain(prefix)
 }
 
 fn format_ip(ip: &IpAddr) -> String {
@@ -155,12 +380,75 @@ pub fn count_lists(
     sources: &[&str],
     output: Output,
 ) -> Result<Vec<DnsListMembership>, DnsCheckError> {
-    queries
-        .iter()
-        .flat_map(|query| {
-            sources
-                .iter()
-                .map(move |&source| lookup(source, query, &output))
-        })
-        .collect()
+    let lists: Vec<ListDefinition> = sources.iter().copied().map(ListDefinition::from).collect();
+    shared_checker(output).count_lists(queries, &lists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_address_networks_are_addresses() {
+        assert!(matches!(Query::from("192.0.2.1/32"), Query::Address(_)));
+        assert!(matches!(Query::from("2001:db8::1/128"), Query::Address(_)));
+        // A /32 is a whole network in IPv6
+        assert_eq!(Query::from("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(Query::from("192.0.2.9/24").to_string(), "192.0.2.0/24");
+        assert!(matches!(Query::from("192.0.2.0/33"), Query::Domain(_)));
+    }
+
+    #[test]
+    fn networks_too_large_for_a_list_are_turned_down() {
+        let ipv4 = ListDefinition::new("bl.test");
+        let domains = ListDefinition {
+            query: QueryType::Domain,
+            ..ListDefinition::new("dbl.test")
+        };
+        let ipv6_48 = ListDefinition {
+            ipv6_prefix: 48,
+            ..ListDefinition::new("bl6.test")
+        };
+        assert!(Query::parse("192.0.2.0/24", Some(&ipv4)).is_ok());
+        assert!(matches!(
+            Query::parse("192.0.0.0/23", Some(&ipv4)),
+            Err(DnsCheckError::TooLarge(_))
+        ));
+        // Only the lists that would check it count
+        assert!(Query::parse("192.0.0.0/16", Some(&domains)).is_ok());
+        assert!(Query::parse("2001:db8::/56", Some(&ipv4)).is_ok());
+        assert!(Query::parse("2001:db8::/40", Some(&ipv4)).is_err());
+        assert!(Query::parse("2001:db8::/40", Some(&ipv6_48)).is_ok());
+    }
+
+    #[test]
+    fn list_queries_are_read_back_from_the_names_we_look_up() {
+        let cases = [
+            ("2.0.0.127", "127.0.0.2"),
+            ("1.2.0.192.", "192.0.2.1"),
+            ("example.com", "example.com"),
+            ("example.com.", "example.com"),
+            // Too few octets, or not octets at all, so they must be names
+            ("2.0.192", "2.0.192"),
+            ("1.2.0.300", "1.2.0.300"),
+        ];
+        for (prefix, query) in &cases {
+            assert_eq!(list_query(prefix).to_string(), *query, "{}", prefix);
+        }
+        assert!(matches!(list_query("2.0.0.127"), Query::Address(_)));
+        assert!(matches!(list_query("1.2.0.300"), Query::Domain(_)));
+    }
+
+    #[test]
+    fn ipv6_list_queries_are_one_nibble_per_label() {
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let name = list_hostname("bl.test", &Query::Address(ip));
+        let prefix = name.strip_suffix("bl.test.").unwrap();
+        assert_eq!(prefix.split('.').filter(|l| !l.is_empty()).count(), 32);
+        assert_eq!(list_query(prefix).to_string(), "2001:db8::1");
+
+        // Nibbles have to be single hex digits
+        let wide = prefix.replacen("1.", "10.", 1);
+        assert!(matches!(list_query(&wide), Query::Domain(_)));
+    }
 }
@@ -0,0 +1,240 @@
+//! What we know about a list: its name, and how to read the answers it gives.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::{default_sentinels, Query, Sentinel, DEFAULT_IPV6_PREFIX};
+
+/// Answers that a list uses to mean a particular kind of listing.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ReturnCode {
+    /// Any answer in an inclusive range.
+    Range {
+        first: IpAddr,
+        last: IpAddr,
+        label: String,
+    },
+    /// Any `127.0.0.x` answer with one of these bits set in its last octet.
+    ///
+    /// URI lists like SURBL and URIBL combine several sub-lists into one, giving each a bit.
+    Bitmask { mask: u8, label: String },
+}
+
+impl ReturnCode {
+    pub fn new(first: IpAddr, last: IpAddr, label: &str) -> ReturnCode {
+        ReturnCode::Range {
+            first,
+            last,
+            label: label.to_string(),
+        }
+    }
+
+    pub fn bitmask(mask: u8, label: &str) -> ReturnCode {
+        ReturnCode::Bitmask {
+            mask,
+            label: label.to_string(),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            ReturnCode::Range { label, .. } | ReturnCode::Bitmask { label, .. } => label,
+        }
+    }
+
+    pub fn matches(&self, answer: &IpAddr) -> bool {
+        match (self, answer) {
+            (ReturnCode::Range { first, last, .. }, answer) => match (first, last, answer) {
+                (IpAddr::V4(first), IpAddr::V4(last), IpAddr::V4(answer)) => {
+                    (first..=last).contains(&answer)
+                }
+                (IpAddr::V6(first), IpAddr::V6(last), IpAddr::V6(answer)) => {
+                    (first..=last).contains(&answer)
+                }
+                _ => false,
+            },
+            (ReturnCode::Bitmask { mask, .. }, IpAddr::V4(answer)) => {
+                let octets = answer.octets();
+                octets[..3] == [127, 0, 0] && octets[3] & mask != 0
+            }
+            (ReturnCode::Bitmask { .. }, IpAddr::V6(_)) => false,
+        }
+    }
+}
+
+impl FromStr for ReturnCode {
+    type Err = String;
+
+    /// Parses `code=label`, where the code is either a single answer or an inclusive range of
+    /// them like `4-7`.  A bare number is short for `127.0.0.x`, which is what most lists use.
+    /// A code like `&8` matches any answer with that bit set instead.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((codes, label)) => ReturnCode::parse(codes, label),
+            None => ReturnCode::parse(s, s),
+        }
+    }
+}
+
+impl ReturnCode {
+    /// Parses the answers half of a return code, giving them the label we're passed.
+    pub fn parse(codes: &str, label: &str) -> Result<ReturnCode, String> {
+        if let Some(mask) = codes.trim().strip_prefix('&') {
+            let mask = match mask.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => mask.parse(),
+            }
+            .map_err(|_| format!("invalid bitmask {:?}", mask))?;
+            return Ok(ReturnCode::bitmask(mask, label.trim()));
+        }
+        let (first, last) = match codes.split_once('-') {
+            Some((first, last)) => (parse_code(first)?, parse_code(last)?),
+            None => {
+                let code = parse_code(codes)?;
+                (code, code)
+            }
+        };
+        Ok(ReturnCode::new(first, last, label.trim()))
+    }
+}
+
+pub(crate) fn parse_code(s: &str) -> Result<IpAddr, String> {
+    let s = s.trim();
+    s.parse::<u8>()
+        .map(|last| Ipv4Addr::new(127, 0, 0, last).into())
+        .or_else(|_| s.parse::<IpAddr>())
+        .map_err(|_| format!("invalid return code {:?}", s))
+}
+
+/// The kinds of query a list will answer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryType {
+    /// We don't know, so we'll send it every query.
+    #[default]
+    Any,
+    /// IP addresses only.
+    #[serde(rename = "ip", alias = "address")]
+    Address,
+    /// Domain names only.
+    Domain,
+}
+
+impl QueryType {
+    pub fn accepts(self, query: &Query) -> bool {
+        matches!(
+            (self, query),
+            (QueryType::Any, _)
+                | (QueryType::Address, Query::Address(_))
+                | (QueryType::Address, Query::Network(..))
+                | (QueryType::Domain, Query::Domain(_))
+        )
+    }
+}
+
+/// How a list's name says it's kept in a local file.
+const FILE_PREFIX: &str = "file:";
+
+/// A list to check, along with which of its answers count as a listing.
+///
+/// A list without any return codes treats every answer as a listing.
+///
+/// A list named like `file:/etc/dabl/blocked` is read from a local file rather than looked up in
+/// DNS.  The file holds addresses, networks and domains in the same format `dabl-dnsbl` serves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListDefinition {
+    pub name: String,
+    /// The local file the list is kept in, if it's not a DNS list.
+    pub file: Option<PathBuf>,
+    pub codes: Vec<ReturnCode>,
+    pub query: QueryType,
+    /// How much a listing here counts for, relative to other lists.
+    pub weight: f64,
+    /// The size of the IPv6 networks the list treats as one, so we check one address in each.
+    pub ipv6_prefix: u8,
+    /// Answers that mean the list refused the query, rather than that it's listed.  Unless
+    /// we're told otherwise, these are the ones the list's operator documents.
+    pub sentinels: Vec<Sentinel>,
+}
+
+impl ListDefinition {
+    pub fn new(name: &str) -> ListDefinition {
+        ListDefinition {
+            name: name.to_string(),
+            file: name.strip_prefix(FILE_PREFIX).map(PathBuf::from),
+            codes: vec![],
+            query: QueryType::default(),
+            weight: 1.0,
+            ipv6_prefix: DEFAULT_IPV6_PREFIX,
+            sentinels: default_sentinels(name),
+        }
+    }
+
+    /// Adds a return code that counts as a listing.
+    pub fn with_code(mut self, code: ReturnCode) -> ListDefinition {
+        self.codes.push(code);
+        self
+    }
+
+    /// Whether an answer from this list means that the query is listed.
+    pub fn lists(&self, answer: &IpAddr) -> bool {
+        self.codes.is_empty() || self.codes.iter().any(|c| c.matches(answer))
+    }
+
+    /// The labels of every return code that matches any of the answers.
+    pub fn labels(&self, answers: &[IpAddr]) -> Vec<String> {
+        let mut labels: Vec<String> = vec![];
+        for code in &self.codes {
+            let label = code.label();
+            if answers.iter().any(|a| code.matches(a)) && !labels.iter().any(|l| l == label) {
+                labels.push(label.to_string());
+            }
+        }
+        labels
+    }
+}
+
+impl From<&str> for ListDefinition {
+    fn from(name: &str) -> Self {
+        ListDefinition::new(name)
+    }
+}
+
+impl FromStr for ListDefinition {
+    type Err = String;
+
+    /// Parses a list name, optionally followed by the return codes that count as listings:
+    /// `zen.spamhaus.org:2=SBL,3=CSS,4-7=XBL`, or `multi.surbl.org:&8=PH,&16=MW,&64=ABUSE,&128=CR`
+    /// for a list that sets a bit for each sub-list.  File lists take them after the path:
+    /// `file:/etc/dabl/blocked:3=LOCAL`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The prefix's colon doesn't start the return codes
+        let skip = if s.starts_with(FILE_PREFIX) {
+            FILE_PREFIX.len()
+        } else {
+            0
+        };
+        match s[skip..].find(':').map(|i| s.split_at(skip + i)) {
+            None => Ok(ListDefinition::new(s)),
+            Some((name, codes)) => codes[1..]
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map(|codes| ListDefinition {
+                    codes,
+                    ..ListDefinition::new(name)
+                }),
+        }
+    }
+}
+
+impl Display for ListDefinition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
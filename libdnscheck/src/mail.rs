@@ -0,0 +1,174 @@
+//! What a mail server should make of our reports, for the tools that plug into one.
+
+use crate::{DnsCheckError, QueryReport};
+
+/// What a mail server should do about a query.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Decision {
+    /// An allow list has it, so there's no need to check anything else.
+    Allow,
+    /// It's listed, with an explanation fit to send back to the client.
+    Block(String),
+    /// We couldn't check it, so the client should try again later.
+    Defer(String),
+    /// Nothing has it.
+    Pass,
+}
+
+impl QueryReport {
+    /// Any listing is enough to block a query, unless we're given a threshold for its score to
+    /// reach.  Nothing is blocked without a listing, however low the threshold.
+    ///
+    /// A failed lookup defers the query, unless the list refused to answer: it'll keep refusing,
+    /// so there's no point in the client trying again.
+    pub fn decide(&self, threshold: Option<f64>) -> Decision {
+        if self.allowed() {
+            return Decision::Allow;
+        }
+
+        let blocks = self.blocks();
+        let listed = match threshold {
+            Some(threshold) => !blocks.is_empty() && self.score() >= threshold,
+            None => !blocks.is_empty(),
+        };
+        if listed {
+            let lists: Vec<&str> = blocks.iter().map(|l| l.list.as_str()).collect();
+            let text = format!("{} is listed by {}", self.query, lists.join(", "));
+            return match blocks.iter().find(|l| !l.reason.is_empty()) {
+                Some(list) => Decision::Block(format!("{}: {}", text, list.reason.join(" "))),
+                None => Decision::Block(text),
+            };
+        }
+
+        let failed = self.lists.iter().find(|l| match &l.error {
+            Some(DnsCheckError::Refused { .. }) | None => false,
+            Some(_) => true,
+        });
+        match failed {
+            Some(list) => Decision::Defer(format!(
+                "Unable to check {} against {}, try again later",
+                self.query, list.list
+            )),
+            None => Decision::Pass,
+        }
+    }
+}
+
+/// The domain a client gave in HELO, unless it's an address literal like `[192.0.2.1]`.
+pub fn helo_domain(helo: &str) -> Option<&str> {
+    let helo = helo.trim().trim_end_matches('.');
+    if helo.starts_with('[') || !helo.contains('.') {
+        None
+    } else {
+        Some(helo)
+    }
+}
+
+/// The domain of an envelope sender like `<user@example.com>`, unless it's the null sender.
+pub fn sender_domain(sender: &str) -> Option<&str> {
+    let sender = sender.trim().trim_start_matches('<').trim_end_matches('>');
+    sender
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim_end_matches('.'))
+        .filter(|domain| !domain.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ListReport, Role};
+    use std::time::Duration;
+
+    fn list(name: &str, role: Role, found: bool, score: f64) -> ListReport {
+        ListReport {
+            list: name.to_string(),
+            role,
+            found,
+            skipped: false,
+            answers: vec![],
+            labels: vec![],
+            score,
+            reason: vec![],
+            elapsed: Duration::from_millis(1),
+            ttl: None,
+            listed: vec![],
+            error: None,
+        }
+    }
+
+    fn report(lists: Vec<ListReport>) -> QueryReport {
+        QueryReport {
+            query: "192.0.2.1".to_string(),
+            lists,
+        }
+    }
+
+    #[test]
+    fn any_listing_blocks_without_a_threshold() {
+        let mut listed = list("bl.example", Role::Block, true, 1.0);
+        listed.reason = vec!["Spam".to_string(), "source".to_string()];
+        let report = report(vec![list("other.example", Role::Block, false, 0.0), listed]);
+        assert_eq!(
+            report.decide(None),
+            Decision::Block("192.0.2.1 is listed by bl.example: Spam source".to_string())
+        );
+    }
+
+    #[test]
+    fn an_allow_listing_wins() {
+        let report = report(vec![
+            list("wl.example", Role::Allow, true, 0.0),
+            list("bl.example", Role::Block, true, 1.0),
+        ]);
+        assert_eq!(report.decide(None), Decision::Allow);
+    }
+
+    #[test]
+    fn a_threshold_needs_the_score_to_reach_it() {
+        let report = report(vec![
+            list("one.example", Role::Block, true, 1.0),
+            list("two.example", Role::Block, true, 0.5),
+        ]);
+        assert_eq!(report.decide(Some(2.0)), Decision::Pass);
+        assert_eq!(
+            report.decide(Some(1.5)),
+            Decision::Block("192.0.2.1 is listed by one.example, two.example".to_string())
+        );
+    }
+
+    #[test]
+    fn a_threshold_of_zero_doesnt_block_unlisted_queries() {
+        let report = report(vec![list("bl.example", Role::Block, false, 0.0)]);
+        assert_eq!(report.decide(Some(0.0)), Decision::Pass);
+        assert_eq!(report.decide(Some(-1.0)), Decision::Pass);
+    }
+
+    #[test]
+    fn failed_lookups_defer_unless_the_list_refused() {
+        let mut failed = list("bl.example", Role::Block, false, 0.0);
+        failed.error = Some(DnsCheckError::Timeout("timed out".to_string()));
+        assert_eq!(
+            report(vec![failed]).decide(None),
+            Decision::Defer(
+                "Unable to check 192.0.2.1 against bl.example, try again later".to_string()
+            )
+        );
+
+        let mut refused = list("bl.example", Role::Block, false, 0.0);
+        refused.error = Some(DnsCheckError::Refused {
+            list: "bl.example".to_string(),
+            answer: "127.255.255.254".parse().unwrap(),
+            reason: "queried through a public resolver".to_string(),
+        });
+        assert_eq!(report(vec![refused]).decide(None), Decision::Pass);
+    }
+
+    #[test]
+    fn helo_and_sender_domains() {
+        assert_eq!(helo_domain("mail.example.com."), Some("mail.example.com"));
+        assert_eq!(helo_domain("[192.0.2.1]"), None);
+        assert_eq!(helo_domain("localhost"), None);
+        assert_eq!(sender_domain("<user@example.com>"), Some("example.com"));
+        assert_eq!(sender_domain("<>"), None);
+    }
+}
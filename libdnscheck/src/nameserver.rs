@@ -0,0 +1,104 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::wire::{RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_TXT};
+use crate::{client, wire, Answer, DnsCheckError, Family, Resolver};
+
+/// Resolves names by sending queries directly to the nameservers we're given.
+///
+/// Some list operators refuse to answer queries relayed through public resolvers, so this lets us
+/// use a local recursive resolver of our own choosing instead of whatever the system is set up
+/// with.
+pub struct NameserverResolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl NameserverResolver {
+    pub fn new(nameservers: Vec<SocketAddr>, timeout: Duration) -> NameserverResolver {
+        NameserverResolver {
+            nameservers,
+            timeout,
+        }
+    }
+
+    /// Uses the nameservers from `/etc/resolv.conf`.
+    pub fn system(timeout: Duration) -> NameserverResolver {
+        NameserverResolver::new(client::system_nameservers(), timeout)
+    }
+
+    /// Returns the data of every answer of the given type, or nothing if the name doesn't exist,
+    /// along with how long we may remember that for.
+    fn query(
+        &self,
+        hostname: &str,
+        rtype: u16,
+        deadline: Instant,
+    ) -> Result<(Vec<Vec<u8>>, Option<u32>), DnsCheckError> {
+        let message = client::query(&self.nameservers, hostname, rtype, deadline)?;
+        match message.rcode {
+            RCODE_NOERROR => {
+                let answers: Vec<_> = message
+                    .answers
+                    .into_iter()
+                    .filter(|r| r.rtype == rtype)
+                    .collect();
+                let ttl = match answers.iter().map(|r| r.ttl).min() {
+                    Some(ttl) => Some(ttl),
+                    None => message.negative_ttl,
+                };
+                Ok((answers.into_iter().map(|r| r.data).collect(), ttl))
+            }
+            RCODE_NXDOMAIN => Ok((vec![], message.negative_ttl)),
+            rcode => Err(DnsCheckError::Nameserver(hostname.to_string(), rcode)),
+        }
+    }
+}
+
+impl Resolver for NameserverResolver {
+    fn resolve(&self, hostname: &str, family: Family) -> Result<Vec<IpAddr>, DnsCheckError> {
+        self.resolve_answer(hostname, family)
+            .map(|answer| answer.addresses)
+    }
+
+    fn resolve_answer(&self, hostname: &str, family: Family) -> Result<Answer, DnsCheckError> {
+        // The timeout covers the whole lookup, however many queries it takes
+        let deadline = Instant::now() + self.timeout;
+        let mut addresses = vec![];
+        // We can only say how long the whole answer is good for if we know for every part of it
+        let mut ttls = vec![];
+        if family != Family::Ipv6 {
+            let (answers, ttl) = self.query(hostname, TYPE_A, deadline)?;
+            for data in answers {
+                if let Ok(octets) = <[u8; 4]>::try_from(data.as_slice()) {
+                    addresses.push(IpAddr::from(octets));
+                }
+            }
+            ttls.push(ttl);
+        }
+        if family != Family::Ipv4 {
+            let (answers, ttl) = self.query(hostname, TYPE_AAAA, deadline)?;
+            for data in answers {
+                if let Ok(octets) = <[u8; 16]>::try_from(data.as_slice()) {
+                    addresses.push(IpAddr::from(octets));
+                }
+            }
+            ttls.push(ttl);
+        }
+        let ttl = ttls
+            .into_iter()
+            .collect::<Option<Vec<u32>>>()
+            .and_then(|ttls| ttls.into_iter().min())
+            .map(|ttl| Duration::from_secs(ttl.into()));
+        Ok(Answer { addresses, ttl })
+    }
+
+    fn resolve_txt(&self, hostname: &str) -> Result<Vec<String>, DnsCheckError> {
+        self.query(hostname, TYPE_TXT, Instant::now() + self.timeout)?
+            .0
+            .iter()
+            .map(|data| wire::txt_data(data))
+            .collect()
+    }
+}
@@ -0,0 +1,122 @@
+//! Checking a whole network, one address at a time.
+//!
+//! Lists are looked up by address, so we check an IPv4 network by checking every address in it.
+//! Lists don't track IPv6 addresses individually: most treat a /64 as a single subscriber, so we
+//! check one address for each network of the size the list uses.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The most addresses we'll check in one network, for each list.  That's a /24 in IPv4, or a /56
+/// of /64s in IPv6.
+pub const MAX_NETWORK_LOOKUPS: usize = 256;
+
+/// The size of the IPv6 networks most lists treat as a single subscriber.
+pub const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+/// Parses `address/prefix`, clearing any bits outside the prefix.
+pub(crate) fn parse_network(s: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = s.split_once('/')?;
+    let address: IpAddr = address.parse().ok()?;
+    let prefix: u8 = prefix.parse().ok()?;
+    if prefix > width(&address) {
+        return None;
+    }
+    Some((first(&address, prefix), prefix))
+}
+
+/// The addresses to check in a network, given the size of the IPv6 networks a list uses, or
+/// nothing if there would be too many of them.
+pub(crate) fn addresses(network: IpAddr, prefix: u8, ipv6_prefix: u8) -> Option<Vec<IpAddr>> {
+    let granularity = match network {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => ipv6_prefix.min(128),
+    };
+    let first = first(&network, prefix);
+    if prefix >= granularity {
+        return Some(vec![first]);
+    }
+    let count = 1u128.checked_shl(u32::from(granularity - prefix))?;
+    if count > MAX_NETWORK_LOOKUPS as u128 {
+        return None;
+    }
+    let step = 1u128 << (u32::from(width(&network)) - u32::from(granularity));
+    let base = bits(&first);
+    Some(
+        (0..count)
+            .map(|i| match network {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from((base + i * step) as u32)),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(base + i * step)),
+            })
+            .collect(),
+    )
+}
+
+fn width(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn bits(address: &IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(ip) => u32::from(*ip).into(),
+        IpAddr::V6(ip) => u128::from(*ip),
+    }
+}
+
+/// The first address in the network.  A prefix longer than the address leaves it as it is.
+fn first(address: &IpAddr, prefix: u8) -> IpAddr {
+    let host_bits = u32::from(width(address).saturating_sub(prefix));
+    let masked = bits(address) & u128::MAX.checked_shl(host_bits).unwrap_or(0);
+    match address {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(masked as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(masked)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checked(network: &str, ipv6_prefix: u8) -> Option<Vec<String>> {
+        let (network, prefix) = parse_network(network).unwrap();
+        addresses(network, prefix, ipv6_prefix)
+            .map(|addresses| addresses.iter().map(IpAddr::to_string).collect())
+    }
+
+    #[test]
+    fn networks_are_checked_an_address_at_a_time() {
+        let addresses = checked("192.0.2.5/30", DEFAULT_IPV6_PREFIX).unwrap();
+        assert_eq!(
+            addresses,
+            vec!["192.0.2.4", "192.0.2.5", "192.0.2.6", "192.0.2.7"]
+        );
+    }
+
+    #[test]
+    fn ipv6_networks_are_checked_a_list_sized_network_at_a_time() {
+        let addresses = checked("2001:db8::/63", DEFAULT_IPV6_PREFIX).unwrap();
+        assert_eq!(addresses, vec!["2001:db8::", "2001:db8:0:1::"]);
+        let addresses = checked("2001:db8::1/80", DEFAULT_IPV6_PREFIX).unwrap();
+        assert_eq!(addresses, vec!["2001:db8::"]);
+    }
+
+    #[test]
+    fn networks_with_too_many_addresses_are_turned_down() {
+        assert_eq!(checked("192.0.2.0/24", 64).map(|a| a.len()), Some(256));
+        assert_eq!(checked("192.0.0.0/23", 64), None);
+        assert_eq!(checked("0.0.0.0/0", 64), None);
+        assert_eq!(checked("::/0", 128), None);
+    }
+
+    #[test]
+    fn prefixes_longer_than_the_address_are_single_addresses() {
+        assert_eq!(parse_network("192.0.2.1/33"), None);
+        let address: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(first(&address, 40), address);
+        assert_eq!(addresses(address, 40, 64), Some(vec![address]));
+        let address: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(addresses(address, 200, 64), Some(vec![address]));
+    }
+}
@@ -0,0 +1,344 @@
+//! Reports of what we found, in a form that's easy to hand on to other tools.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+
+use crate::{DnsCheckError, Lookup, Role};
+
+/// How a tool should print its results.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Format {
+    /// Lines of text, for people to read.
+    #[default]
+    Text,
+    /// A single JSON array once every query has been checked.
+    Json,
+    /// A JSON object on a line of its own as soon as each query has been checked.
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "jsonl" | "json-lines" | "ndjson" => Ok(Format::JsonLines),
+            _ => Err(format!(
+                "unknown format {:?}, expected one of text, json or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+/// Everything we learned from looking a query up in one list.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ListReport {
+    pub list: String,
+    pub role: Role,
+    pub found: bool,
+    pub skipped: bool,
+    pub answers: Vec<IpAddr>,
+    pub labels: Vec<String>,
+    pub score: f64,
+    /// The TXT records the list published to explain a listing, if we fetched them.
+    pub reason: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "seconds"))]
+    pub elapsed: Duration,
+    /// How much longer the list's answer is good for, if the resolver told us.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "optional_seconds"))]
+    pub ttl: Option<Duration>,
+    /// For a network, the addresses in it that the list has.
+    pub listed: Vec<IpAddr>,
+    pub error: Option<DnsCheckError>,
+}
+
+impl ListReport {
+    pub fn new(role: Role, lookup: Lookup, reason: Vec<String>) -> ListReport {
+        let mut report = ListReport {
+            list: lookup.list.name.clone(),
+            role,
+            found: false,
+            skipped: false,
+            answers: vec![],
+            labels: vec![],
+            score: 0.0,
+            reason,
+            elapsed: lookup.elapsed,
+            ttl: None,
+            listed: vec![],
+            error: None,
+        };
+        match lookup.result {
+            Ok(membership) => {
+                report.found = membership.found;
+                report.skipped = membership.skipped;
+                report.answers = membership.answers;
+                report.labels = membership.labels;
+                report.score = membership.score;
+                report.ttl = membership.ttl;
+                report.listed = membership.listed;
+            }
+            Err(e) => report.error = Some(e),
+        }
+        report
+    }
+}
+
+/// Everything we learned about one query.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct QueryReport {
+    pub query: String,
+    pub lists: Vec<ListReport>,
+}
+
+impl QueryReport {
+    /// Whether any allow list has the query.
+    pub fn allowed(&self) -> bool {
+        self.lists.iter().any(|l| l.role == Role::Allow && l.found)
+    }
+
+    /// The block lists that have the query, unless an allow list has it too.
+    pub fn blocks(&self) -> Vec<&ListReport> {
+        if self.allowed() {
+            return vec![];
+        }
+        self.lists
+            .iter()
+            .filter(|l| l.role == Role::Block && l.found)
+            .collect()
+    }
+
+    /// The total weight of the block lists that have the query.
+    pub fn score(&self) -> f64 {
+        self.blocks().iter().fold(0.0, |total, l| total + l.score)
+    }
+}
+
+/// How a tool exits when asked to tell outcomes apart, rather than counting listings.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExitStatus {
+    /// Every lookup succeeded, and nothing was listed.
+    Clean,
+    /// At least one query was listed.
+    Listed,
+    /// Nothing was listed, and at least one query was on an allow list.
+    AllowListed,
+    /// Nothing was listed, but some lookups failed so we can't be sure.
+    PartialFailure,
+    /// Every lookup failed, or we couldn't check anything at all.
+    TotalFailure,
+}
+
+impl ExitStatus {
+    pub fn code(self) -> i32 {
+        match self {
+            ExitStatus::Clean => 0,
+            ExitStatus::Listed => 1,
+            ExitStatus::AllowListed => 2,
+            ExitStatus::PartialFailure => 3,
+            ExitStatus::TotalFailure => 4,
+        }
+    }
+}
+
+/// Keeps track of what we've found across every query, to work out how to exit.
+#[derive(Debug, Default)]
+pub struct Tally {
+    listed: bool,
+    allowed: bool,
+    lookups: usize,
+    failures: usize,
+}
+
+impl Tally {
+    /// Adds a query's report, where `listed` is whether the tool counts the query as listed.
+    pub fn add(&mut self, report: &QueryReport, listed: bool) {
+        self.listed |= listed;
+        self.allowed |= report.allowed();
+        for list in report.lists.iter().filter(|l| !l.skipped) {
+            self.lookups += 1;
+            if list.error.is_some() {
+                self.failures += 1;
+            }
+        }
+    }
+
+    /// Counts a query we couldn't look up at all as a failed lookup.
+    pub fn fail(&mut self) {
+        self.lookups += 1;
+        self.failures += 1;
+    }
+
+    /// A listing is worth reporting even if other lookups failed, but a failure means we can't
+    /// call anything clean, and neither can having looked nothing up.
+    pub fn status(&self) -> ExitStatus {
+        if self.failures == self.lookups {
+            ExitStatus::TotalFailure
+        } else if self.listed {
+            ExitStatus::Listed
+        } else if self.failures > 0 {
+            ExitStatus::PartialFailure
+        } else if self.allowed {
+            ExitStatus::AllowListed
+        } else {
+            ExitStatus::Clean
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(feature = "serde")]
+pub(crate) fn optional_seconds<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => seconds(duration, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for DnsCheckError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut error = serializer.serialize_struct("DnsCheckError", 2)?;
+        error.serialize_field("kind", self.kind())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(role: Role, found: bool, error: Option<DnsCheckError>) -> ListReport {
+        ListReport {
+            list: "list.example".to_string(),
+            role,
+            found,
+            skipped: false,
+            answers: vec![],
+            labels: vec![],
+            score: if found { 1.0 } else { 0.0 },
+            reason: vec![],
+            elapsed: Duration::from_millis(1),
+            ttl: None,
+            listed: vec![],
+            error,
+        }
+    }
+
+    fn failed(role: Role) -> ListReport {
+        list(
+            role,
+            false,
+            Some(DnsCheckError::Timeout("timed out".to_string())),
+        )
+    }
+
+    fn tally(reports: Vec<(Vec<ListReport>, bool)>) -> ExitStatus {
+        let mut tally = Tally::default();
+        for (lists, listed) in reports {
+            let report = QueryReport {
+                query: "192.0.2.1".to_string(),
+                lists,
+            };
+            tally.add(&report, listed);
+        }
+        tally.status()
+    }
+
+    #[test]
+    fn nothing_listed_is_clean() {
+        let status = tally(vec![(vec![list(Role::Block, false, None)], false)]);
+        assert_eq!(status, ExitStatus::Clean);
+    }
+
+    #[test]
+    fn a_listing_outranks_failed_lookups() {
+        let status = tally(vec![(
+            vec![list(Role::Block, true, None), failed(Role::Block)],
+            true,
+        )]);
+        assert_eq!(status, ExitStatus::Listed);
+    }
+
+    #[test]
+    fn a_failed_lookup_means_we_cant_call_it_clean() {
+        let status = tally(vec![(
+            vec![list(Role::Block, false, None), failed(Role::Block)],
+            false,
+        )]);
+        assert_eq!(status, ExitStatus::PartialFailure);
+    }
+
+    #[test]
+    fn a_failed_lookup_outranks_being_allow_listed() {
+        let status = tally(vec![(
+            vec![list(Role::Allow, true, None), failed(Role::Block)],
+            false,
+        )]);
+        assert_eq!(status, ExitStatus::PartialFailure);
+    }
+
+    #[test]
+    fn allow_listed_queries_are_reported() {
+        let status = tally(vec![(
+            vec![list(Role::Allow, true, None), list(Role::Block, true, None)],
+            false,
+        )]);
+        assert_eq!(status, ExitStatus::AllowListed);
+    }
+
+    #[test]
+    fn every_lookup_failing_is_a_total_failure() {
+        let status = tally(vec![
+            (vec![failed(Role::Allow), failed(Role::Block)], false),
+            (vec![failed(Role::Block)], false),
+        ]);
+        assert_eq!(status, ExitStatus::TotalFailure);
+    }
+
+    #[test]
+    fn queries_we_couldnt_look_up_are_failures() {
+        let mut tally = Tally::default();
+        tally.fail();
+        assert_eq!(tally.status(), ExitStatus::TotalFailure);
+        let report = QueryReport {
+            query: "192.0.2.1".to_string(),
+            lists: vec![list(Role::Block, false, None)],
+        };
+        tally.add(&report, false);
+        assert_eq!(tally.status(), ExitStatus::PartialFailure);
+    }
+
+    #[test]
+    fn skipped_lists_arent_lookups() {
+        let mut skipped = list(Role::Block, false, None);
+        skipped.skipped = true;
+        let status = tally(vec![(vec![failed(Role::Block), skipped], false)]);
+        assert_eq!(status, ExitStatus::TotalFailure);
+    }
+
+    #[test]
+    fn looking_nothing_up_is_a_total_failure() {
+        assert_eq!(tally(vec![]), ExitStatus::TotalFailure);
+        assert_eq!(tally(vec![(vec![], false)]), ExitStatus::TotalFailure);
+    }
+}
@@ -0,0 +1,148 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::dbus::DBusResolver;
+use crate::system::SystemResolver;
+use crate::DnsCheckError::{NoDBus, NoResolved};
+use crate::{DnsCheckError, Family, Output};
+
+/// The addresses a list gave us for a name, and how long we may remember them for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Answer {
+    pub addresses: Vec<IpAddr>,
+    /// How long the answer is good for, if the resolver can tell us.  For a name without any
+    /// addresses, this is the negative caching time from the zone's SOA record.
+    pub ttl: Option<Duration>,
+}
+
+/// Answers the DNS questions we need to ask of a list.
+///
+/// Implementations should treat a name that doesn't exist as an empty answer rather than an error,
+/// as that's how a list tells us that a query isn't listed.
+pub trait Resolver: Send + Sync {
+    /// Looks up the addresses of the given family for a fully-qualified name.
+    fn resolve(&self, hostname: &str, family: Family) -> Result<Vec<IpAddr>, DnsCheckError>;
+
+    /// Looks up addresses like `resolve`, along with their TTL if the resolver knows it.
+    fn resolve_answer(&self, hostname: &str, family: Family) -> Result<Answer, DnsCheckError> {
+        Ok(Answer {
+            addresses: self.resolve(hostname, family)?,
+            ttl: None,
+        })
+    }
+
+    /// Looks up the TXT records for a fully-qualified name.
+    fn resolve_txt(&self, hostname: &str) -> Result<Vec<String>, DnsCheckError>;
+}
+
+/// Uses systemd-resolved if it's available, otherwise falls back to the system resolver.
+pub struct AutoResolver {
+    output: Output,
+    dbus: Option<DBusResolver>,
+    system: SystemResolver,
+}
+
+impl AutoResolver {
+    pub fn connect(output: Output, timeout: Duration) -> AutoResolver {
+        let dbus = match DBusResolver::connect(timeout) {
+            Ok(resolver) => Some(resolver),
+            Err(NoDBus) => {
+                if output == Output::Verbose {
+                    eprintln!("DBus not compiled in, falling back to internal resolution")
+                }
+                None
+            }
+            Err(e) => {
+                if output == Output::Verbose {
+                    eprintln!("DBus resolution failed: {:?}", e)
+                }
+                None
+            }
+        };
+
+        AutoResolver {
+            output,
+            dbus,
+            system: SystemResolver::new(timeout),
+        }
+    }
+}
+
+impl Resolver for AutoResolver {
+    fn resolve(&self, hostname: &str, family: Family) -> Result<Vec<IpAddr>, DnsCheckError> {
+        self.resolve_answer(hostname, family)
+            .map(|answer| answer.addresses)
+    }
+
+    fn resolve_answer(&self, hostname: &str, family: Family) -> Result<Answer, DnsCheckError> {
+        if let Some(dbus) = &self.dbus {
+            match dbus.resolve_answer(hostname, family) {
+                Err(NoResolved(e)) => {
+                    if self.output == Output::Verbose {
+                        eprintln!("DBus resolution failed: {:?}", e)
+                    }
+                }
+                r => return r,
+            }
+        }
+
+        self.system.resolve_answer(hostname, family)
+    }
+
+    fn resolve_txt(&self, hostname: &str) -> Result<Vec<String>, DnsCheckError> {
+        if let Some(dbus) = &self.dbus {
+            match dbus.resolve_txt(hostname) {
+                Err(NoResolved(e)) => {
+                    if self.output == Output::Verbose {
+                        eprintln!("DBus resolution failed: {:?}", e)
+                    }
+                }
+                r => return r,
+            }
+        }
+
+        self.system.resolve_txt(hostname)
+    }
+}
+
+/// The resolvers a user can choose between by name.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResolverKind {
+    /// systemd-resolved if it's available, otherwise the system resolver.
+    Auto,
+    /// Only systemd-resolved.
+    Resolved,
+    /// Only the system resolver.
+    System,
+}
+
+impl ResolverKind {
+    pub fn connect(
+        self,
+        output: Output,
+        timeout: Duration,
+    ) -> Result<Box<dyn Resolver>, DnsCheckError> {
+        Ok(match self {
+            ResolverKind::Auto => Box::new(AutoResolver::connect(output, timeout)),
+            ResolverKind::Resolved => Box::new(DBusResolver::connect(timeout)?),
+            ResolverKind::System => Box::new(SystemResolver::new(timeout)),
+        })
+    }
+}
+
+impl FromStr for ResolverKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(ResolverKind::Auto),
+            "resolved" => Ok(ResolverKind::Resolved),
+            "system" => Ok(ResolverKind::System),
+            _ => Err(format!(
+                "unknown resolver {:?}, expected one of auto, resolved or system",
+                s
+            )),
+        }
+    }
+}
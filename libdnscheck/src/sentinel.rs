@@ -0,0 +1,92 @@
+//! Answers that list operators use to tell us they've refused a query, rather than that it's
+//! listed.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::list::parse_code;
+
+/// A range of answers that a list uses to report an error instead of a listing.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Sentinel {
+    pub first: IpAddr,
+    pub last: IpAddr,
+    pub reason: String,
+}
+
+impl Sentinel {
+    pub fn new(first: IpAddr, last: IpAddr, reason: &str) -> Sentinel {
+        Sentinel {
+            first,
+            last,
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn single(answer: IpAddr, reason: &str) -> Sentinel {
+        Sentinel::new(answer, answer, reason)
+    }
+
+    /// Parses answers the same way as return codes: `255`, or an inclusive range like `250-255`,
+    /// where a bare number is short for `127.0.0.x`.
+    pub fn parse(codes: &str, reason: &str) -> Result<Sentinel, String> {
+        let (first, last) = match codes.split_once('-') {
+            Some((first, last)) => (parse_code(first)?, parse_code(last)?),
+            None => {
+                let code = parse_code(codes)?;
+                (code, code)
+            }
+        };
+        Ok(Sentinel::new(first, last, reason.trim()))
+    }
+
+    pub fn matches(&self, answer: &IpAddr) -> bool {
+        match (self.first, self.last, answer) {
+            (IpAddr::V4(first), IpAddr::V4(last), IpAddr::V4(answer)) => {
+                (first..=last).contains(answer)
+            }
+            (IpAddr::V6(first), IpAddr::V6(last), IpAddr::V6(answer)) => {
+                (first..=last).contains(answer)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+    Ipv4Addr::new(a, b, c, d).into()
+}
+
+/// The error answers documented by the operator of a list, if we know of any.
+///
+/// We recognise lists by the domain they're published under, so this also covers the
+/// subscription mirrors that operators run under their own domains.
+pub fn default_sentinels(list: &str) -> Vec<Sentinel> {
+    let list = list.trim_end_matches('.').to_ascii_lowercase();
+    let operated_by = |domain: &str| list == domain || list.ends_with(&format!(".{}", domain));
+
+    if operated_by("spamhaus.org") || operated_by("spamhaus.net") {
+        // https://www.spamhaus.org/faq/section/DNSBL%20Usage#200
+        vec![
+            Sentinel::single(v4(127, 255, 255, 252), "typing error in DNSBL name"),
+            Sentinel::single(v4(127, 255, 255, 254), "query via public/open resolver"),
+            Sentinel::single(v4(127, 255, 255, 255), "excessive number of queries"),
+            Sentinel::new(
+                v4(127, 255, 255, 0),
+                v4(127, 255, 255, 255),
+                "error reported by Spamhaus",
+            ),
+        ]
+    } else if operated_by("surbl.org") {
+        vec![Sentinel::single(
+            v4(127, 0, 0, 1),
+            "query refused: access blocked",
+        )]
+    } else if operated_by("uribl.com") {
+        vec![Sentinel::single(
+            v4(127, 0, 0, 1),
+            "query refused: excessive volume or public resolver",
+        )]
+    } else {
+        vec![]
+    }
+}
@@ -0,0 +1,147 @@
+//! Listening for the mail servers that want to ask us about their clients.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// The most connections we'll handle at once.  Any more wait to be accepted until one closes.
+const MAX_CONNECTIONS: usize = 128;
+
+/// Where to listen for connections.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Listen {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    /// Parses addresses the way Sendmail and Postfix write them: `unix:/path/to/socket`, or
+    /// `inet:port@host` and `inet:host:port`.  A plain path or `host:port` works too.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:").or_else(|| s.strip_prefix("local:")) {
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        if s.starts_with('/') {
+            return Ok(Listen::Unix(PathBuf::from(s)));
+        }
+        let address = match s.strip_prefix("inet:").or_else(|| s.strip_prefix("inet6:")) {
+            Some(spec) => match spec.split_once('@') {
+                Some((port, host)) if host.contains(':') && !host.starts_with('[') => {
+                    format!("[{}]:{}", host, port)
+                }
+                Some((port, host)) => format!("{}:{}", host, port),
+                None if spec.contains(':') => spec.to_string(),
+                None => format!("0.0.0.0:{}", spec),
+            },
+            None => s.to_string(),
+        };
+        address
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .map(Listen::Tcp)
+            .ok_or_else(|| format!("invalid listen address {:?}", s))
+    }
+}
+
+/// A connection from a client, over either kind of socket.
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+impl Listen {
+    /// Accepts connections forever, handling each one on a thread of its own, unless we can't
+    /// listen at all.  Errors accepting or handling a single connection are only logged.
+    pub fn serve<F>(&self, handler: F) -> io::Result<()>
+    where
+        F: Fn(&mut dyn Connection) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let slots = Arc::new(Slots::default());
+        match self {
+            Listen::Unix(path) => {
+                // A socket left behind by a previous run would stop us binding, but we won't
+                // remove anything else
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        fs::remove_file(path)?;
+                    }
+                }
+                let listener = UnixListener::bind(path)?;
+                accept(&handler, &slots, || {
+                    listener.accept().map(|(stream, _)| stream)
+                })
+            }
+            Listen::Tcp(address) => {
+                let listener = TcpListener::bind(address)?;
+                accept(&handler, &slots, || {
+                    listener.accept().map(|(stream, _)| stream)
+                })
+            }
+        }
+    }
+}
+
+/// Hands each connection to the handler on a thread of its own, waiting for a free slot before
+/// accepting the next one.
+fn accept<F, S>(handler: &Arc<F>, slots: &Arc<Slots>, mut next: impl FnMut() -> io::Result<S>) -> !
+where
+    F: Fn(&mut dyn Connection) -> io::Result<()> + Send + Sync + 'static,
+    S: Connection + 'static,
+{
+    loop {
+        let slot = Slots::take(slots);
+        let mut stream = match next() {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        let handler = Arc::clone(handler);
+        thread::spawn(move || {
+            if let Err(e) = handler(&mut stream) {
+                eprintln!("Error: {}", e);
+            }
+            drop(slot);
+        });
+    }
+}
+
+/// How many connections we're handling, so we can wait for one to close when there are too many.
+#[derive(Default)]
+struct Slots {
+    busy: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A connection being handled, which frees its slot when dropped, even if the handler panics.
+struct Slot(Arc<Slots>);
+
+impl Slots {
+    fn take(slots: &Arc<Slots>) -> Slot {
+        let mut busy = slots.busy.lock().expect("Slots lock poisoned");
+        while *busy >= MAX_CONNECTIONS {
+            busy = slots.freed.wait(busy).expect("Slots lock poisoned");
+        }
+        *busy += 1;
+        Slot(Arc::clone(slots))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Ok(mut busy) = self.0.busy.lock() {
+            *busy -= 1;
+        }
+        self.0.freed.notify_one();
+    }
+}
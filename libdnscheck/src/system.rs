@@ -0,0 +1,92 @@
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use dns_lookup::{getaddrinfo, AddrInfoHints, LookupError, LookupErrorKind};
+
+use crate::{DnsCheckError, Family, NameserverResolver, Resolver};
+
+/// Resolves names the way the rest of the system does, using `getaddrinfo` for addresses and the
+/// nameservers from `/etc/resolv.conf` for TXT records.
+pub struct SystemResolver {
+    timeout: Duration,
+}
+
+impl SystemResolver {
+    pub fn new(timeout: Duration) -> SystemResolver {
+        SystemResolver { timeout }
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, hostname: &str, family: Family) -> Result<Vec<IpAddr>, DnsCheckError> {
+        // There's no way to give getaddrinfo a timeout, so we leave it running in the background
+        // if it takes too long.
+        let (tx, rx) = mpsc::channel();
+        let name = hostname.to_string();
+        let hints = AddrInfoHints {
+            address: family.af(),
+            ..AddrInfoHints::default()
+        };
+        thread::spawn(move || {
+            let result = getaddrinfo(Some(&name), None, Some(hints)).map(|a| a.collect::<Vec<_>>());
+            let _ = tx.send(result);
+        });
+        let result = rx
+            .recv_timeout(self.timeout)
+            .map_err(|_| DnsCheckError::Timeout(hostname.to_string()))?;
+
+        let addrinfo = match result {
+            Ok(a) => a,
+            Err(e) => return not_found(e),
+        };
+
+        // getaddrinfo returns one entry per socket type, so we'll see each address more than once
+        let mut answers: Vec<IpAddr> = vec![];
+        for info in addrinfo {
+            let ip = info?.sockaddr.ip();
+            if family.matches(&ip) && !answers.contains(&ip) {
+                answers.push(ip);
+            }
+        }
+
+        Ok(answers)
+    }
+
+    fn resolve_txt(&self, hostname: &str) -> Result<Vec<String>, DnsCheckError> {
+        NameserverResolver::system(self.timeout).resolve_txt(hostname)
+    }
+}
+
+/// A name that doesn't exist isn't listed, and neither is one without any addresses of the
+/// family we asked for, as when a list only publishes A records and we want AAAA.
+fn not_found(e: LookupError) -> Result<Vec<IpAddr>, DnsCheckError> {
+    match e.kind() {
+        LookupErrorKind::NoName | LookupErrorKind::NoData => Ok(vec![]),
+        _ => Err(DnsCheckError::Unknown(io::Error::from(e).into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_names_and_missing_families_are_clean() {
+        assert!(not_found(LookupError::new(libc::EAI_NONAME))
+            .unwrap()
+            .is_empty());
+        #[cfg(target_os = "linux")]
+        assert!(not_found(LookupError::new(libc::EAI_NODATA))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn other_failures_are_errors() {
+        assert!(not_found(LookupError::new(libc::EAI_AGAIN)).is_err());
+        assert!(not_found(LookupError::new(libc::EAI_FAIL)).is_err());
+    }
+}
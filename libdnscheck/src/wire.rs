@@ -0,0 +1,371 @@
+//! Just enough of the DNS wire format (RFC 1035) to ask for records that `getaddrinfo` won't give
+//! us, and to read the answers.
+
+use std::convert::TryInto;
+
+use crate::DnsCheckError;
+
+pub const CLASS_IN: u16 = 1;
+pub const TYPE_A: u16 = 1;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+const FLAG_RD: u16 = 0x0100;
+const FLAG_TC: u16 = 0x0200;
+const HEADER_LEN: usize = 12;
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub rtype: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub id: u16,
+    pub truncated: bool,
+    pub rcode: u8,
+    /// The name and type asked about, so we can tell whether this answers our question.
+    pub question: Option<(String, u16)>,
+    pub answers: Vec<Record>,
+    /// How long we may remember that there's no answer, from the SOA record in the authority
+    /// section (RFC 2308).
+    pub negative_ttl: Option<u32>,
+}
+
+fn malformed(what: &str) -> DnsCheckError {
+    DnsCheckError::Malformed(what.to_string())
+}
+
+/// Builds a recursive query for a single name and record type.
+pub fn build_query(id: u16, name: &str, rtype: u16) -> Result<Vec<u8>, DnsCheckError> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RD.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 6]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(malformed(&format!("invalid name {:?}", name)));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&rtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+/// Parses a complete response message, keeping only the answer section.
+pub fn parse_message(buf: &[u8]) -> Result<Message, DnsCheckError> {
+    if buf.len() < HEADER_LEN {
+        return Err(malformed("short header"));
+    }
+    let id = read_u16(buf, 0)?;
+    let flags = read_u16(buf, 2)?;
+    let questions = read_u16(buf, 4)?;
+    let answer_count = read_u16(buf, 6)?;
+    let authority_count = read_u16(buf, 8)?;
+
+    let mut pos = HEADER_LEN;
+    let mut question = None;
+    for _ in 0..questions {
+        let (name, next) = read_name(buf, pos)?;
+        question.get_or_insert((name, read_u16(buf, next)?));
+        pos = next + 4;
+    }
+
+    let truncated = flags & FLAG_TC != 0;
+    let mut answers = Vec::with_capacity(answer_count as usize);
+    for _ in 0..answer_count {
+        match read_record(buf, pos) {
+            Ok((record, next)) => {
+                answers.push(record);
+                pos = next;
+            }
+            // We'll ask again over TCP, so there's no point complaining about a partial answer
+            Err(_) if truncated => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // An authority section we can't read only costs us the negative TTL, so we don't fail on it
+    let mut negative_ttl = None;
+    if !truncated {
+        for _ in 0..authority_count {
+            let (record, next) = match read_record(buf, pos) {
+                Ok(r) => r,
+                Err(_) => break,
+            };
+            if record.rtype == TYPE_SOA {
+                if let Ok(minimum) = soa_minimum(buf, next - record.data.len()) {
+                    negative_ttl = Some(record.ttl.min(minimum));
+                }
+                break;
+            }
+            pos = next;
+        }
+    }
+
+    Ok(Message {
+        id,
+        truncated,
+        rcode: (flags & 0xF) as u8,
+        question,
+        answers,
+        negative_ttl,
+    })
+}
+
+impl Message {
+    /// Whether this is the answer to our query, rather than a stray or forged one.
+    pub fn answers_query(&self, id: u16, name: &str, rtype: u16) -> bool {
+        self.id == id
+            && self.question.as_ref().is_some_and(|(asked, asked_type)| {
+                *asked_type == rtype
+                    && asked
+                        .trim_end_matches('.')
+                        .eq_ignore_ascii_case(name.trim_end_matches('.'))
+            })
+    }
+}
+
+/// Parses a single resource record, as systemd-resolved hands them to us.
+#[cfg_attr(not(all(feature = "dbus", target_os = "linux")), allow(dead_code))]
+pub fn parse_record(buf: &[u8]) -> Result<Record, DnsCheckError> {
+    read_record(buf, 0).map(|(record, _)| record)
+}
+
+/// Joins the character-strings of a TXT record into a single string.
+pub fn txt_data(data: &[u8]) -> Result<String, DnsCheckError> {
+    let mut text = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = data[pos] as usize;
+        let chunk = data
+            .get(pos + 1..pos + 1 + len)
+            .ok_or_else(|| malformed("truncated TXT string"))?;
+        text.extend_from_slice(chunk);
+        pos += 1 + len;
+    }
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+fn read_record(buf: &[u8], pos: usize) -> Result<(Record, usize), DnsCheckError> {
+    let (_, pos) = read_name(buf, pos)?;
+    let rtype = read_u16(buf, pos)?;
+    let ttl = read_u32(buf, pos + 4)?;
+    let len = read_u16(buf, pos + 8)? as usize;
+    let start = pos + 10;
+    let data = buf
+        .get(start..start + len)
+        .ok_or_else(|| malformed("truncated record data"))?
+        .to_vec();
+    Ok((Record { rtype, ttl, data }, start + len))
+}
+
+/// Reads the MINIMUM field of SOA record data starting at `pos`.  The names before it may be
+/// compressed, so we need the whole message to skip them.
+fn soa_minimum(buf: &[u8], pos: usize) -> Result<u32, DnsCheckError> {
+    let (_, pos) = read_name(buf, pos)?;
+    let (_, pos) = read_name(buf, pos)?;
+    // Skip the serial, refresh, retry and expire fields
+    read_u32(buf, pos + 16)
+}
+
+fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize), DnsCheckError> {
+    let mut labels: Vec<String> = vec![];
+    // Where to carry on reading once we've followed a compression pointer
+    let mut resume = None;
+    let mut jumps = 0;
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| malformed("truncated name"))? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xC0 == 0xC0 {
+            jumps += 1;
+            if jumps > 64 {
+                return Err(malformed("compression loop"));
+            }
+            let target = (read_u16(buf, pos)? & 0x3FFF) as usize;
+            resume.get_or_insert(pos + 2);
+            pos = target;
+            continue;
+        }
+        let label = buf
+            .get(pos + 1..pos + 1 + len)
+            .ok_or_else(|| malformed("truncated label"))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Ok((labels.join("."), resume.unwrap_or(pos)))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Result<u32, DnsCheckError> {
+    buf.get(pos..pos + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or_else(|| malformed("truncated message"))
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16, DnsCheckError> {
+    buf.get(pos..pos + 2)
+        .and_then(|b| b.try_into().ok())
+        .map(u16::from_be_bytes)
+        .ok_or_else(|| malformed("truncated message"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    const NAME: &str = "2.0.0.127.bl.test";
+    /// Where `bl.test` starts in the question, for records to point back to.
+    const ZONE: u8 = 22;
+
+    /// A response to our query for `NAME`, with the given flags and counts, followed by `records`.
+    fn response(flags: u16, answers: u16, authority: u16, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = build_query(ID, NAME, TYPE_A).unwrap();
+        buf[2..4].copy_from_slice(&(0x8000 | FLAG_RD | flags).to_be_bytes());
+        buf[6..8].copy_from_slice(&answers.to_be_bytes());
+        buf[8..10].copy_from_slice(&authority.to_be_bytes());
+        for record in records {
+            buf.extend_from_slice(record);
+        }
+        buf
+    }
+
+    /// A record owned by the name at `owner`.
+    fn record(owner: u8, rtype: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0xC0, owner];
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn soa(minimum: u32) -> Vec<u8> {
+        let mut data = vec![0xC0, ZONE, 10];
+        data.extend_from_slice(b"hostmaster");
+        data.extend_from_slice(&[0xC0, ZONE]);
+        for value in &[1, 3600, 600, 86400, minimum] {
+            data.extend_from_slice(&u32::to_be_bytes(*value));
+        }
+        data
+    }
+
+    #[test]
+    fn queries_are_built_label_by_label() {
+        let query = build_query(ID, "a.example.", TYPE_TXT).unwrap();
+        assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&query[12..], b"\x01a\x07example\x00\x00\x10\x00\x01");
+
+        assert!(build_query(ID, "a..example", TYPE_A).is_err());
+        assert!(build_query(ID, &format!("{}.example", "a".repeat(64)), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn answers_are_read_and_matched_to_the_question() {
+        let buf = response(
+            0,
+            2,
+            0,
+            &[
+                record(12, TYPE_A, 300, &[127, 0, 0, 2]),
+                record(12, TYPE_A, 200, &[127, 0, 0, 4]),
+            ],
+        );
+        let message = parse_message(&buf).unwrap();
+        assert_eq!(message.rcode, RCODE_NOERROR);
+        assert!(!message.truncated);
+        assert_eq!(message.question, Some((NAME.to_string(), TYPE_A)));
+        let answers: Vec<_> = message
+            .answers
+            .iter()
+            .map(|r| (r.ttl, &r.data[..]))
+            .collect();
+        assert_eq!(
+            answers,
+            [(300, &[127, 0, 0, 2][..]), (200, &[127, 0, 0, 4][..])]
+        );
+
+        assert!(message.answers_query(ID, "2.0.0.127.BL.test.", TYPE_A));
+        assert!(!message.answers_query(ID + 1, NAME, TYPE_A));
+        assert!(!message.answers_query(ID, "3.0.0.127.bl.test", TYPE_A));
+        assert!(!message.answers_query(ID, NAME, TYPE_TXT));
+    }
+
+    #[test]
+    fn compression_loops_are_malformed() {
+        let mut buf = response(0, 1, 0, &[]);
+        // An owner name that points at itself
+        let at = buf.len() as u8;
+        buf.extend_from_slice(&record(at, TYPE_A, 300, &[127, 0, 0, 2]));
+        match parse_message(&buf) {
+            Err(DnsCheckError::Malformed(what)) => assert_eq!(what, "compression loop"),
+            result => panic!("expected a compression loop, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn truncated_answers_are_only_an_error_without_the_tc_flag() {
+        let mut partial = record(12, TYPE_A, 300, &[127, 0, 0, 4]);
+        partial.truncate(8);
+        let records = [record(12, TYPE_A, 300, &[127, 0, 0, 2]), partial];
+
+        let message = parse_message(&response(FLAG_TC, 2, 0, &records)).unwrap();
+        assert!(message.truncated);
+        assert_eq!(message.answers.len(), 1);
+
+        assert!(parse_message(&response(0, 2, 0, &records)).is_err());
+        assert!(parse_message(&response(0, 0, 0, &[])[..11]).is_err());
+    }
+
+    #[test]
+    fn negative_ttl_is_the_lesser_of_the_soa_ttl_and_minimum() {
+        let nxdomain = |ttl, minimum| {
+            let buf = response(
+                u16::from(RCODE_NXDOMAIN),
+                0,
+                1,
+                &[record(ZONE, TYPE_SOA, ttl, &soa(minimum))],
+            );
+            parse_message(&buf).unwrap()
+        };
+        let message = nxdomain(3600, 60);
+        assert_eq!(message.rcode, RCODE_NXDOMAIN);
+        assert!(message.answers.is_empty());
+        assert_eq!(message.negative_ttl, Some(60));
+        assert_eq!(nxdomain(30, 60).negative_ttl, Some(30));
+    }
+
+    #[test]
+    fn a_broken_authority_section_only_loses_the_negative_ttl() {
+        let mut broken = soa(60);
+        broken.truncate(20);
+        let records = [record(ZONE, TYPE_SOA, 3600, &broken)];
+        let message = parse_message(&response(0, 0, 1, &records)).unwrap();
+        assert_eq!(message.negative_ttl, None);
+
+        let garbage = response(0, 0, 1, &[vec![0xC0]]);
+        assert_eq!(parse_message(&garbage).unwrap().negative_ttl, None);
+    }
+
+    #[test]
+    fn txt_strings_are_joined() {
+        assert_eq!(txt_data(b"\x05Spam \x04seen").unwrap(), "Spam seen");
+        assert_eq!(txt_data(b"").unwrap(), "");
+        assert!(txt_data(b"\x05Spam").is_err());
+    }
+}
@@ -0,0 +1,568 @@
+//! Lists kept in local files, in the formats rbldnsd reads.
+//!
+//! Each line holds an entry, optionally followed by the answer and TXT reason to give for it:
+//!
+//! ```text
+//! # The answer and reason for entries that don't give their own
+//! :127.0.0.2:Listed, see https://example.com/lookup?$
+//! 192.0.2.0/24
+//! 198.51.100.7 :127.0.0.3:Sent us spam
+//! !192.0.2.1
+//! ```
+//!
+//! `$` in a reason stands for whatever was looked up.  Entries starting with `!` are exceptions
+//! to wider entries.  IPv4 entries can leave out trailing octets to cover a whole network, as in
+//! `192.0.2` for `192.0.2.0/24`, or give a range of last octets like `192.0.2.1-9`.  Domain
+//! entries like `example.com` only match that name, `*.example.com` matches every name under it,
+//! and `.example.com` matches both.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{DnsCheckError, Query};
+
+/// The answer we give for entries that don't say otherwise.
+pub const DEFAULT_ANSWER: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+/// Which kind of entries a file holds, named after rbldnsd's dataset types.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ZoneFormat {
+    /// IPv4 addresses and networks: `ip4set`, `ip4trie` or `ip4tset`.
+    Ipv4,
+    /// IPv6 addresses and networks: `ip6trie` or `ip6tset`.
+    Ipv6,
+    /// Domain names: `dnset`.
+    Domain,
+    /// Any mix of addresses, networks and domains, as in a file-backed list.  Each entry is
+    /// whichever it looks like, and a plain domain covers every name under it as well.
+    Mixed,
+}
+
+impl FromStr for ZoneFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ip4set" | "ip4trie" | "ip4tset" => Ok(ZoneFormat::Ipv4),
+            "ip6trie" | "ip6tset" => Ok(ZoneFormat::Ipv6),
+            "dnset" => Ok(ZoneFormat::Domain),
+            _ => Err(format!(
+                "unknown list format {:?}, expected one of ip4set, ip4trie, ip4tset, ip6trie, \
+                 ip6tset or dnset",
+                s
+            )),
+        }
+    }
+}
+
+/// What we say about a listed entry.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Listing {
+    pub answer: Ipv4Addr,
+    pub reason: Option<String>,
+}
+
+impl Listing {
+    /// The reason for the listing, with `$` replaced by whatever was looked up.
+    pub fn reason(&self, query: &str) -> Option<String> {
+        self.reason.as_ref().map(|r| r.replace('$', query))
+    }
+}
+
+impl Default for Listing {
+    fn default() -> Self {
+        Listing {
+            answer: DEFAULT_ANSWER,
+            reason: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Listed(Listing),
+    Excluded,
+}
+
+impl Entry {
+    fn listing(&self) -> Option<&Listing> {
+        match self {
+            Entry::Listed(listing) => Some(listing),
+            Entry::Excluded => None,
+        }
+    }
+}
+
+/// A binary trie of network prefixes, so the most specific entry for an address wins.
+#[derive(Debug, Default, Clone)]
+struct PrefixTrie {
+    nodes: Vec<PrefixNode>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct PrefixNode {
+    children: [Option<usize>; 2],
+    entry: Option<Entry>,
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, bits: u128, width: u32, prefix: u32, entry: Entry) {
+        if self.nodes.is_empty() {
+            self.nodes.push(PrefixNode::default());
+        }
+        let mut node = 0;
+        for i in 0..prefix {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(PrefixNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].entry = Some(entry);
+    }
+
+    fn lookup(&self, bits: u128, width: u32) -> Option<&Entry> {
+        let mut node = self.nodes.first()?;
+        let mut found = node.entry.as_ref();
+        for i in 0..width {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            node = match node.children[bit] {
+                Some(child) => &self.nodes[child],
+                None => break,
+            };
+            found = node.entry.as_ref().or(found);
+        }
+        found
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// A trie of domain labels, starting from the top level.
+#[derive(Debug, Default, Clone)]
+struct DomainNode {
+    children: HashMap<String, DomainNode>,
+    /// The entry for this name itself.
+    exact: Option<Entry>,
+    /// The entry for every name under this one.
+    below: Option<Entry>,
+}
+
+impl DomainNode {
+    fn insert(&mut self, domain: &str, exact: bool, below: bool, entry: Entry) {
+        let mut node = self;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.to_string()).or_default();
+        }
+        if exact {
+            node.exact = Some(entry.clone());
+        }
+        if below {
+            node.below = Some(entry);
+        }
+    }
+
+    fn lookup(&self, domain: &str) -> Option<&Entry> {
+        let mut node = self;
+        let mut found = None;
+        let mut labels = domain.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => return found,
+            };
+            if labels.peek().is_some() {
+                found = node.below.as_ref().or(found);
+            }
+        }
+        node.exact.as_ref().or(found)
+    }
+}
+
+/// Everything in one or more list files, ready to look queries up in.
+#[derive(Debug, Default, Clone)]
+pub struct Zone {
+    ipv4: PrefixTrie,
+    ipv6: PrefixTrie,
+    domains: DomainNode,
+}
+
+impl Zone {
+    /// Reads a list file into a zone of its own.
+    pub fn load(path: &Path, format: ZoneFormat) -> Result<Zone, DnsCheckError> {
+        let mut zone = Zone::default();
+        zone.read(path, format)?;
+        Ok(zone)
+    }
+
+    /// Adds the entries in a list file to the zone.
+    pub fn read(&mut self, path: &Path, format: ZoneFormat) -> Result<(), DnsCheckError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| DnsCheckError::Config(format!("reading {}: {}", path.display(), e)))?;
+        self.parse(&contents, format)
+            .map_err(|e| DnsCheckError::Config(format!("{}:{}", path.display(), e)))
+    }
+
+    /// Adds entries in the given format, returning the line number and problem on failure.
+    fn parse(&mut self, contents: &str, format: ZoneFormat) -> Result<(), String> {
+        let mut default = Listing::default();
+        for (number, line) in contents.lines().enumerate() {
+            let at = |e: String| format!("{}: {}", number + 1, e);
+            let line = line.trim();
+            // We skip rbldnsd's `$` directives along with comments: dabl-dnsbl sets the TTL
+            // and SOA itself
+            if line.is_empty() || line.starts_with(&['#', ';', '$'][..]) {
+                continue;
+            }
+            if line.starts_with(':') {
+                default = listing(line, &Listing::default()).map_err(at)?;
+                continue;
+            }
+
+            let (entry, value) = match line.split_once(char::is_whitespace) {
+                Some((entry, value)) => (entry, value.trim()),
+                None => (line, ""),
+            };
+            let (entry, listed) = match entry.strip_prefix('!') {
+                Some(entry) => (entry, Entry::Excluded),
+                None => (entry, Entry::Listed(listing(value, &default).map_err(at)?)),
+            };
+            let kind = match format {
+                ZoneFormat::Mixed => classify(entry),
+                format => format,
+            };
+            match kind {
+                ZoneFormat::Ipv4 => {
+                    for (network, prefix) in ipv4_entry(entry).map_err(at)? {
+                        let bits = u32::from(network).into();
+                        self.ipv4.insert(bits, 32, prefix, listed.clone());
+                    }
+                }
+                ZoneFormat::Ipv6 => {
+                    let (network, prefix) = ipv6_entry(entry).map_err(at)?;
+                    self.ipv6.insert(u128::from(network), 128, prefix, listed);
+                }
+                ZoneFormat::Domain | ZoneFormat::Mixed => {
+                    let entry = entry.trim_end_matches('.').to_ascii_lowercase();
+                    if let Some(domain) = entry.strip_prefix("*.") {
+                        self.domains.insert(domain, false, true, listed);
+                    } else if let Some(domain) = entry.strip_prefix('.') {
+                        self.domains.insert(domain, true, true, listed);
+                    } else if format == ZoneFormat::Mixed {
+                        self.domains.insert(&entry, true, true, listed);
+                    } else {
+                        self.domains.insert(&entry, true, false, listed);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The most specific entry for a query, unless it's an exception.
+    pub fn lookup(&self, query: &Query) -> Option<&Listing> {
+        let entry = match query {
+            // As with a DNS list, a network is looked up by its first address
+            Query::Address(IpAddr::V4(ip)) | Query::Network(IpAddr::V4(ip), _) => {
+                self.ipv4.lookup(u32::from(*ip).into(), 32)
+            }
+            Query::Address(IpAddr::V6(ip)) | Query::Network(IpAddr::V6(ip), _) => {
+                self.ipv6.lookup(u128::from(*ip), 128)
+            }
+            Query::Domain(domain) => self
+                .domains
+                .lookup(&domain.trim_end_matches('.').to_ascii_lowercase()),
+        };
+        entry.and_then(Entry::listing)
+    }
+
+    /// Whether the zone has any address entries, rather than only domains.
+    pub fn has_addresses(&self) -> bool {
+        !self.ipv4.is_empty() || !self.ipv6.is_empty()
+    }
+}
+
+/// Which kind of entry a line of a mixed list holds.
+fn classify(entry: &str) -> ZoneFormat {
+    if entry.contains(':') {
+        ZoneFormat::Ipv6
+    } else if entry.starts_with(|c: char| c.is_ascii_digit()) && ipv4_entry(entry).is_ok() {
+        ZoneFormat::Ipv4
+    } else {
+        ZoneFormat::Domain
+    }
+}
+
+/// Parses `:answer:reason`, or just a reason, falling back to the default for anything left out.
+fn listing(value: &str, default: &Listing) -> Result<Listing, String> {
+    let value = value.trim();
+    let (answer, reason) = match value.strip_prefix(':') {
+        Some(rest) => match rest.split_once(':') {
+            Some((answer, reason)) => (answer.trim(), reason.trim()),
+            None => (rest.trim(), ""),
+        },
+        None => ("", value),
+    };
+    let answer = if answer.is_empty() {
+        default.answer
+    } else {
+        answer
+            .parse::<u8>()
+            .map(|last| Ipv4Addr::new(127, 0, 0, last))
+            .or_else(|_| answer.parse())
+            .map_err(|_| invalid("answer", answer))?
+    };
+    let reason = if reason.is_empty() {
+        default.reason.clone()
+    } else {
+        Some(reason.to_string())
+    };
+    Ok(Listing { answer, reason })
+}
+
+/// Parses an IPv4 entry into the networks it covers.
+fn ipv4_entry(entry: &str) -> Result<Vec<(Ipv4Addr, u32)>, String> {
+    if let Some((network, prefix)) = entry.split_once('/') {
+        let prefix = prefix
+            .parse()
+            .ok()
+            .filter(|p| *p <= 32)
+            .ok_or_else(|| invalid("prefix length", prefix))?;
+        let (network, _) = partial_ipv4(network)?;
+        return Ok(vec![(network, prefix)]);
+    }
+    if let Some((first, last)) = entry.split_once('-') {
+        let (first, octets) = partial_ipv4(first)?;
+        let last: u8 = last.parse().map_err(|_| invalid("range", entry))?;
+        let start = first.octets()[3];
+        if octets != 4 || last < start {
+            return Err(invalid("range", entry));
+        }
+        let base = first.octets();
+        return Ok((start..=last)
+            .map(|o| (Ipv4Addr::new(base[0], base[1], base[2], o), 32))
+            .collect());
+    }
+    let (network, octets) = partial_ipv4(entry)?;
+    Ok(vec![(network, octets * 8)])
+}
+
+/// Parses an address that may leave out trailing octets, returning how many it gave.
+fn partial_ipv4(s: &str) -> Result<(Ipv4Addr, u32), String> {
+    let parts: Vec<&str> = s.split('.').collect();
+    if parts.is_empty() || parts.len() > 4 {
+        return Err(invalid("address", s));
+    }
+    let mut octets = [0; 4];
+    for (octet, part) in octets.iter_mut().zip(&parts) {
+        *octet = part.parse().map_err(|_| invalid("address", s))?;
+    }
+    Ok((Ipv4Addr::from(octets), parts.len() as u32))
+}
+
+fn ipv6_entry(entry: &str) -> Result<(Ipv6Addr, u32), String> {
+    let (network, prefix) = match entry.split_once('/') {
+        Some((network, prefix)) => (
+            network,
+            prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= 128)
+                .ok_or_else(|| invalid("prefix length", prefix))?,
+        ),
+        None => (entry, 128),
+    };
+    let network = network.parse().map_err(|_| invalid("address", network))?;
+    Ok((network, prefix))
+}
+
+fn invalid(what: &str, value: &str) -> String {
+    format!("invalid {} {:?}", what, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(contents: &str, format: ZoneFormat) -> Zone {
+        let mut zone = Zone::default();
+        zone.parse(contents, format).unwrap();
+        zone
+    }
+
+    /// The last octet of the answer for each query, or nothing if it isn't listed.
+    fn answers(zone: &Zone, queries: &[&str]) -> Vec<Option<u8>> {
+        queries
+            .iter()
+            .map(|q| zone.lookup(&Query::from(*q)).map(|l| l.answer.octets()[3]))
+            .collect()
+    }
+
+    #[test]
+    fn formats_have_rbldnsd_names() {
+        assert_eq!("ip4set".parse(), Ok(ZoneFormat::Ipv4));
+        assert_eq!("IP4TRIE".parse(), Ok(ZoneFormat::Ipv4));
+        assert_eq!("ip6tset".parse(), Ok(ZoneFormat::Ipv6));
+        assert_eq!("dnset".parse(), Ok(ZoneFormat::Domain));
+        assert!("combined".parse::<ZoneFormat>().is_err());
+    }
+
+    #[test]
+    fn ipv4_entries_can_leave_out_octets_or_give_a_range() {
+        let zone = zone(
+            "10\n192.0.2\n198.51.100.7-9\n203.0.113.0/25 :3\n",
+            ZoneFormat::Ipv4,
+        );
+        assert_eq!(
+            answers(
+                &zone,
+                &[
+                    "10.200.0.1",
+                    "192.0.2.77",
+                    "192.0.3.1",
+                    "198.51.100.6",
+                    "198.51.100.7",
+                    "198.51.100.9",
+                    "198.51.100.10",
+                    "203.0.113.127",
+                    "203.0.113.128",
+                ]
+            ),
+            [
+                Some(2),
+                Some(2),
+                None,
+                None,
+                Some(2),
+                Some(2),
+                None,
+                Some(3),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn the_most_specific_entry_wins_and_exclusions_punch_holes() {
+        let zone = zone(
+            "192.0.2.0/24\n!192.0.2.0/28\n192.0.2.5 :4\n!2001:db8::1\n2001:db8::/32 :6\n",
+            ZoneFormat::Mixed,
+        );
+        assert_eq!(
+            answers(
+                &zone,
+                &[
+                    "192.0.2.1",
+                    "192.0.2.5",
+                    "192.0.2.16",
+                    "2001:db8::1",
+                    "2001:db8::2",
+                    "2001:db9::1",
+                ]
+            ),
+            [None, Some(4), Some(2), None, Some(6), None]
+        );
+        assert!(zone.has_addresses());
+    }
+
+    #[test]
+    fn defaults_and_reasons_apply_to_the_entries_after_them() {
+        let zone = zone(
+            "# A comment\n$TTL 300\n192.0.2.1\n:127.0.0.3:Listed, see https://example.com/?$\n\
+             192.0.2.2\n192.0.2.3 :4:Our own reason\n192.0.2.4 Just a reason\n192.0.2.5 :5\n",
+            ZoneFormat::Ipv4,
+        );
+        let listing = |query| zone.lookup(&Query::from(query)).unwrap();
+        assert_eq!(listing("192.0.2.1"), &Listing::default());
+        assert_eq!(listing("192.0.2.2").answer, Ipv4Addr::new(127, 0, 0, 3));
+        assert_eq!(
+            listing("192.0.2.2").reason("192.0.2.2").unwrap(),
+            "Listed, see https://example.com/?192.0.2.2"
+        );
+        assert_eq!(
+            listing("192.0.2.3").reason("192.0.2.3").unwrap(),
+            "Our own reason"
+        );
+        assert_eq!(listing("192.0.2.4").answer, Ipv4Addr::new(127, 0, 0, 3));
+        assert_eq!(
+            listing("192.0.2.4").reason.as_deref(),
+            Some("Just a reason")
+        );
+        assert_eq!(listing("192.0.2.5").answer, Ipv4Addr::new(127, 0, 0, 5));
+        assert_eq!(
+            listing("192.0.2.5").reason("192.0.2.5").unwrap(),
+            "Listed, see https://example.com/?192.0.2.5"
+        );
+    }
+
+    #[test]
+    fn domain_entries_cover_the_name_names_under_it_or_both() {
+        let contents = "exact.example\n*.below.example\n.both.example :3\n!no.both.example\n";
+        let names = [
+            "exact.example",
+            "www.exact.example",
+            "below.example",
+            "www.below.example",
+            "both.example",
+            "a.b.both.example",
+            "no.both.example",
+            "yes.no.both.example",
+            "EXACT.example.",
+        ];
+
+        let dnset = zone(contents, ZoneFormat::Domain);
+        assert_eq!(
+            answers(&dnset, &names),
+            [
+                Some(2),
+                None,
+                None,
+                Some(2),
+                Some(3),
+                Some(3),
+                None,
+                Some(3),
+                Some(2)
+            ]
+        );
+        assert!(!dnset.has_addresses());
+
+        // In a mixed list, a plain domain covers everything under it too
+        let mixed = zone(contents, ZoneFormat::Mixed);
+        assert_eq!(answers(&mixed, &names[..2]), [Some(2), Some(2)]);
+    }
+
+    #[test]
+    fn mixed_lists_tell_entries_apart_by_their_look() {
+        assert_eq!(classify("192.0.2.1"), ZoneFormat::Ipv4);
+        assert_eq!(classify("192.0.2"), ZoneFormat::Ipv4);
+        assert_eq!(classify("2001:db8::/32"), ZoneFormat::Ipv6);
+        assert_eq!(classify("example.com"), ZoneFormat::Domain);
+        assert_eq!(classify("3com.example"), ZoneFormat::Domain);
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_line() {
+        let error = |contents| {
+            Zone::default()
+                .parse(contents, ZoneFormat::Ipv4)
+                .unwrap_err()
+        };
+        assert_eq!(error("# fine\n192.0.2.1 :300"), "2: invalid answer \"300\"");
+        assert_eq!(error("192.0.2.9-1"), "1: invalid range \"192.0.2.9-1\"");
+        assert_eq!(error("192.0.2-5"), "1: invalid range \"192.0.2-5\"");
+        assert_eq!(error("192.0.2.0/33"), "1: invalid prefix length \"33\"");
+        assert_eq!(error("192.0.2.256"), "1: invalid address \"192.0.2.256\"");
+        assert_eq!(error("1.2.3.4.5"), "1: invalid address \"1.2.3.4.5\"");
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use structopt::clap::ErrorKind::{HelpDisplayed, VersionDisplayed};
+use structopt::StructOpt;
+
+use libdnscheck::{
+    helo_domain, parse_nameserver, sender_domain, Checker, Config, Connection, Decision,
+    ListDefinition, Listen, Output, Overrides, Query, ResolverKind,
+};
+
+use crate::protocol::{
+    read_command, write_reply, Command, Reply, NO_BODY, NO_DATA, NO_END_OF_HEADERS, NO_HEADERS,
+    NO_HELO, NO_MAIL, NO_RCPT, NO_UNKNOWN,
+};
+
+mod protocol;
+
+fn main() {
+    if let Err(err) = main_() {
+        if let Some(err) = err.downcast_ref::<structopt::clap::Error>() {
+            let out = io::stdout();
+            writeln!(&mut out.lock(), "{}", err.message).expect("Error writing Error to stdout");
+            if matches!(err.kind, HelpDisplayed | VersionDisplayed) {
+                std::process::exit(0)
+            }
+        } else {
+            eprintln!("Error: {:?}", err);
+        }
+        std::process::exit(1)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt()]
+struct Arguments {
+    #[structopt(
+        short,
+        long,
+        help = "Where to listen: unix:/path, inet:port@host or host:port"
+    )]
+    /// The socket to listen for the MTA on, in the same form as Sendmail's milter configuration:
+    /// `unix:/var/run/dabl-milter.sock' for a Unix socket, or `inet:8890@localhost' for TCP.  A
+    /// plain path or `host:port' works too.
+    listen: Listen,
+    #[structopt(short, long, number_of_values = 1, help = "A DNS block list")]
+    /// A DNS block list, optionally followed by return codes as for dabl.
+    block: Vec<ListDefinition>,
+    #[structopt(short, long, number_of_values = 1, help = "A DNS allow list")]
+    /// A DNS allow list, optionally followed by return codes as for dabl.  Mail from an address on
+    /// an allow list is accepted without checking any block lists.
+    allow: Vec<ListDefinition>,
+    #[structopt(
+        long = "set",
+        number_of_values = 1,
+        help = "A named set of lists from the configuration file"
+    )]
+    /// Check the allow and block lists in a set defined in the configuration file.  If you don't
+    /// give any lists at all, we'll use the set called `default' if there is one.
+    sets: Vec<String>,
+    #[structopt(long, parse(from_os_str), help = "Read configuration from this file")]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Reject mail that scores at least this much")]
+    /// Add up the weights of the block lists a connection is found in, and only reject it if its
+    /// score reaches this threshold.  Without a threshold, any listing is enough.
+    threshold: Option<f64>,
+    #[structopt(long, help = "Also check the domain given in HELO")]
+    helo: bool,
+    #[structopt(long, help = "Also check the domain of the envelope sender")]
+    sender: bool,
+    #[structopt(short, long, help = "How many lookups to run at once [default: 8]")]
+    jobs: Option<usize>,
+    #[structopt(long, help = "Seconds to wait for each lookup [default: 30]")]
+    timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "How to resolve names: auto, resolved or system [default: auto]"
+    )]
+    resolver: Option<ResolverKind>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = parse_nameserver),
+        conflicts_with = "resolver",
+        help = "Send queries directly to this nameserver"
+    )]
+    nameserver: Vec<SocketAddr>,
+    #[structopt(short, long, help = "Log every decision, not just rejections")]
+    verbose: bool,
+}
+
+/// Everything each connection needs to make its decisions.
+struct Milter {
+    checker: Checker,
+    allow: Vec<ListDefinition>,
+    block: Vec<ListDefinition>,
+    threshold: Option<f64>,
+    helo: bool,
+    sender: bool,
+    verbose: bool,
+}
+
+fn main_() -> Result<()> {
+    let args: Arguments = Arguments::from_args_safe()?;
+
+    let config = Config::load(args.config.as_deref())?;
+    let defaults = &config.defaults;
+
+    let (allow, block) = config.lists(&args.sets, &args.allow, &args.block)?;
+
+    let checker = config.checker(
+        Output::Normal,
+        Overrides {
+            jobs: args.jobs,
+            timeout: args.timeout,
+            resolver: args.resolver,
+            nameservers: args.nameserver,
+            ..Overrides::default()
+        },
+    )?;
+
+    let milter = Milter {
+        checker,
+        allow,
+        block,
+        threshold: args.threshold.or(defaults.threshold),
+        helo: args.helo,
+        sender: args.sender,
+        verbose: args.verbose,
+    };
+
+    args.listen.serve(move |stream| serve(&milter, stream))?;
+    Ok(())
+}
+
+/// Answers the MTA's commands until it closes the connection.
+fn serve(milter: &Milter, stream: &mut dyn Connection) -> io::Result<()> {
+    while let Some(command) = read_command(stream)? {
+        let reply = match &command {
+            Command::Negotiate { version, protocol } => {
+                // We'd rather not hear about anything we won't check
+                let mut unwanted =
+                    NO_RCPT | NO_DATA | NO_HEADERS | NO_END_OF_HEADERS | NO_BODY | NO_UNKNOWN;
+                if !milter.helo {
+                    unwanted |= NO_HELO;
+                }
+                if !milter.sender {
+                    unwanted |= NO_MAIL;
+                }
+                Reply::negotiate(*version, *protocol, unwanted)
+            }
+            Command::Connect {
+                hostname,
+                address: Some(address),
+            } => milter.decide(hostname, Query::Address(*address)),
+            Command::Helo(helo) if milter.helo => match helo_domain(helo) {
+                Some(domain) => milter.decide(helo, Query::Domain(domain)),
+                None => Reply::Continue,
+            },
+            Command::Mail(args) if milter.sender => {
+                let sender = args.first().map(String::as_str).unwrap_or_default();
+                match sender_domain(sender) {
+                    Some(domain) => milter.decide(sender, Query::Domain(domain)),
+                    None => Reply::Continue,
+                }
+            }
+            Command::Quit => return Ok(()),
+            // Including mail submitted locally, which doesn't come from anywhere we can check
+            _ => Reply::Continue,
+        };
+        if command.wants_reply() {
+            write_reply(stream, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+impl Milter {
+    /// Works out what to tell the MTA about a query, logging anything we turn away.
+    fn decide(&self, context: &str, query: Query) -> Reply {
+        let report = self.checker.check(query, &self.allow, &self.block, true);
+        for error in report.lists.iter().filter_map(|l| l.error.as_ref()) {
+            eprintln!("Warning: {}", error);
+        }
+        let reply = match report.decide(self.threshold) {
+            // Anyone can claim a HELO name or a sender, so those being allowed only means they
+            // aren't blocked: the rest of the connection still gets checked
+            Decision::Allow if matches!(query, Query::Address(_)) => Reply::Accept,
+            Decision::Allow => Reply::Continue,
+            Decision::Block(text) => Reply::Reject(text),
+            Decision::Defer(text) => Reply::TempFail(text),
+            Decision::Pass => Reply::Continue,
+        };
+        match &reply {
+            Reply::Reject(text) | Reply::TempFail(text) => eprintln!("{}: {}", context, text),
+            reply if self.verbose => eprintln!("{}: {} {:?}", context, report.query, reply),
+            _ => {}
+        }
+        reply
+    }
+}
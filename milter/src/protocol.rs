@@ -0,0 +1,335 @@
+//! Just enough of the Sendmail milter protocol (version 6) to see where mail is coming from, and
+//! to tell the MTA what we think of it.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+pub const VERSION: u32 = 6;
+
+// Steps of the conversation the MTA can leave out if we ask it to
+pub const NO_HELO: u32 = 0x02;
+pub const NO_MAIL: u32 = 0x04;
+pub const NO_RCPT: u32 = 0x08;
+pub const NO_BODY: u32 = 0x10;
+pub const NO_HEADERS: u32 = 0x20;
+pub const NO_END_OF_HEADERS: u32 = 0x40;
+pub const NO_UNKNOWN: u32 = 0x100;
+pub const NO_DATA: u32 = 0x200;
+
+/// Commands longer than this are either broken or hostile.
+const MAX_COMMAND: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Command {
+    /// The MTA's protocol version, and the steps it's willing to leave out.
+    Negotiate {
+        version: u32,
+        protocol: u32,
+    },
+    /// Macro values for the next command, which we don't need.
+    Macros,
+    /// A new SMTP connection, from an address unless it came over a local socket.
+    Connect {
+        hostname: String,
+        address: Option<IpAddr>,
+    },
+    Helo(String),
+    /// The envelope sender, followed by any ESMTP arguments.
+    Mail(Vec<String>),
+    /// The current message is finished with, but the connection isn't.
+    Abort,
+    /// The MTA has finished with the connection.
+    Quit,
+    /// The MTA is about to reuse this milter connection for a new SMTP connection.
+    QuitNewConnection,
+    /// Any other step, which only needs us to let it carry on.
+    Other,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Reply {
+    Negotiate {
+        version: u32,
+        actions: u32,
+        protocol: u32,
+    },
+    /// Accept the connection or message without asking us anything more about it.
+    Accept,
+    Continue,
+    /// Reject permanently, with an SMTP reply code, enhanced status code and text.
+    Reject(String),
+    /// Reject temporarily, with an SMTP reply code, enhanced status code and text.
+    TempFail(String),
+}
+
+impl Reply {
+    /// Agrees on the older of our protocol versions, and asks the MTA to leave out the steps we
+    /// don't want, though only those it's offered to leave out.
+    pub fn negotiate(version: u32, offered: u32, unwanted: u32) -> Reply {
+        Reply::Negotiate {
+            version: VERSION.min(version),
+            actions: 0,
+            protocol: unwanted & offered,
+        }
+    }
+}
+
+impl Command {
+    /// Whether the MTA waits for a reply to the command.
+    pub fn wants_reply(&self) -> bool {
+        !matches!(
+            self,
+            Command::Macros | Command::Abort | Command::Quit | Command::QuitNewConnection
+        )
+    }
+}
+
+/// Reads the next command, or nothing if the MTA has closed the connection.
+pub fn read_command<R: Read + ?Sized>(input: &mut R) -> io::Result<Option<Command>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_COMMAND {
+        return Err(invalid(format!("command length {}", len)));
+    }
+    let mut buf = vec![0; len];
+    input.read_exact(&mut buf)?;
+
+    let data = &buf[1..];
+    let command = match buf[0] {
+        b'O' => Command::Negotiate {
+            version: read_u32(data, 0)?,
+            // Next come the changes the MTA will let us make to messages, but we don't make any
+            protocol: read_u32(data, 8)?,
+        },
+        b'D' => Command::Macros,
+        b'C' => {
+            let mut fields = data.splitn(2, |b| *b == 0);
+            let hostname = text(fields.next().unwrap_or_default());
+            let rest = fields.next().unwrap_or_default();
+            // The family is followed by a port and the address, unless it's unknown
+            let address = match rest.first() {
+                Some(b'4') | Some(b'6') if rest.len() > 3 => {
+                    let address = strings(&rest[3..]).into_iter().next().unwrap_or_default();
+                    // IPv6 addresses sometimes come with a prefix, as in `IPv6:2001:db8::1`
+                    let address = address.strip_prefix("IPv6:").unwrap_or(&address);
+                    address.parse().ok()
+                }
+                _ => None,
+            };
+            Command::Connect { hostname, address }
+        }
+        b'H' => Command::Helo(strings(data).into_iter().next().unwrap_or_default()),
+        b'M' => Command::Mail(strings(data)),
+        b'A' => Command::Abort,
+        b'Q' => Command::Quit,
+        b'K' => Command::QuitNewConnection,
+        _ => Command::Other,
+    };
+    Ok(Some(command))
+}
+
+pub fn write_reply<W: Write + ?Sized>(output: &mut W, reply: &Reply) -> io::Result<()> {
+    let mut buf = vec![];
+    match reply {
+        Reply::Negotiate {
+            version,
+            actions,
+            protocol,
+        } => {
+            buf.push(b'O');
+            buf.extend_from_slice(&version.to_be_bytes());
+            buf.extend_from_slice(&actions.to_be_bytes());
+            buf.extend_from_slice(&protocol.to_be_bytes());
+        }
+        Reply::Accept => buf.push(b'a'),
+        Reply::Continue => buf.push(b'c'),
+        Reply::Reject(text) => reply_code(&mut buf, "550 5.7.1", text),
+        Reply::TempFail(text) => reply_code(&mut buf, "451 4.7.1", text),
+    }
+    output.write_all(&(buf.len() as u32).to_be_bytes())?;
+    output.write_all(&buf)?;
+    output.flush()
+}
+
+fn reply_code(buf: &mut Vec<u8>, code: &str, text: &str) {
+    // The MTA expands `%' in replies, and a line break would end the reply early
+    let text: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .replace('%', "%%");
+    buf.push(b'y');
+    buf.extend_from_slice(format!("{} {}", code, text).as_bytes());
+    buf.push(0);
+}
+
+fn read_u32(data: &[u8], pos: usize) -> io::Result<u32> {
+    data.get(pos..pos + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or_else(|| invalid("truncated negotiation".to_string()))
+}
+
+/// Splits NUL-terminated strings.
+fn strings(data: &[u8]) -> Vec<String> {
+    data.split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(text)
+        .collect()
+}
+
+fn text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid milter {}", what),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A command as the MTA sends it, with its length in front.
+    fn packet(command: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = ((data.len() + 1) as u32).to_be_bytes().to_vec();
+        buf.push(command);
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn read(buf: &[u8]) -> io::Result<Option<Command>> {
+        read_command(&mut &buf[..])
+    }
+
+    fn connect(family: u8, address: &str) -> Option<IpAddr> {
+        let mut data = b"mail.example\0".to_vec();
+        data.push(family);
+        data.extend_from_slice(&25u16.to_be_bytes());
+        data.extend_from_slice(address.as_bytes());
+        data.push(0);
+        match read(&packet(b'C', &data)).unwrap() {
+            Some(Command::Connect { hostname, address }) => {
+                assert_eq!(hostname, "mail.example");
+                address
+            }
+            command => panic!("expected a connection, got {:?}", command),
+        }
+    }
+
+    fn written(reply: &Reply) -> Vec<u8> {
+        let mut buf = vec![];
+        write_reply(&mut buf, reply).unwrap();
+        buf
+    }
+
+    #[test]
+    fn connections_come_with_their_address() {
+        assert_eq!(connect(b'4', "192.0.2.1"), Some([192, 0, 2, 1].into()));
+        assert_eq!(connect(b'6', "2001:db8::1"), "2001:db8::1".parse().ok());
+        assert_eq!(
+            connect(b'6', "IPv6:2001:db8::1"),
+            "2001:db8::1".parse().ok()
+        );
+        assert_eq!(connect(b'4', "not an address"), None);
+
+        // Local and unknown connections have no port or address at all
+        let local = packet(b'C', b"localhost\0L");
+        assert!(matches!(
+            read(&local).unwrap(),
+            Some(Command::Connect { address: None, .. })
+        ));
+        let unknown = packet(b'C', b"localhost\0U");
+        assert!(matches!(
+            read(&unknown).unwrap(),
+            Some(Command::Connect { address: None, .. })
+        ));
+    }
+
+    #[test]
+    fn helo_and_mail_are_nul_terminated_strings() {
+        match read(&packet(b'H', b"mail.example\0")).unwrap() {
+            Some(Command::Helo(helo)) => assert_eq!(helo, "mail.example"),
+            command => panic!("expected HELO, got {:?}", command),
+        }
+        match read(&packet(b'M', b"<user@example.com>\0SIZE=1000\0")).unwrap() {
+            Some(Command::Mail(args)) => assert_eq!(args, ["<user@example.com>", "SIZE=1000"]),
+            command => panic!("expected MAIL, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn only_some_commands_want_a_reply() {
+        let commands: Vec<_> = [b'D', b'A', b'Q', b'K', b'R', b'T']
+            .iter()
+            .map(|c| read(&packet(*c, b"")).unwrap().unwrap().wants_reply())
+            .collect();
+        assert_eq!(commands, [false, false, false, false, true, true]);
+    }
+
+    #[test]
+    fn a_closed_connection_is_the_end_but_a_broken_command_is_an_error() {
+        assert!(read(b"").unwrap().is_none());
+        assert!(read(&[0, 0, 0, 0]).is_err());
+        assert!(read(&[0, 0x20, 0, 0]).is_err());
+        assert!(read(&packet(b'H', b"mail")[..6]).is_err());
+        assert!(read(&packet(b'O', &[0, 0, 0, 6, 0, 0, 0, 0x1F])).is_err());
+    }
+
+    #[test]
+    fn negotiation_only_asks_to_leave_out_what_the_mta_offered() {
+        let mut data = vec![];
+        for value in &[2u32, 0x1FF, NO_HELO | NO_RCPT | NO_BODY] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        let (version, offered) = match read(&packet(b'O', &data)).unwrap() {
+            Some(Command::Negotiate { version, protocol }) => (version, protocol),
+            command => panic!("expected a negotiation, got {:?}", command),
+        };
+        let reply = Reply::negotiate(version, offered, NO_RCPT | NO_MAIL | NO_BODY | NO_DATA);
+        assert_eq!(
+            reply,
+            Reply::Negotiate {
+                version: 2,
+                actions: 0,
+                protocol: NO_RCPT | NO_BODY,
+            }
+        );
+        let mut expected = packet(b'O', &[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0x18]);
+        // Replies are written with the same layout as commands
+        expected[3] = 13;
+        assert_eq!(written(&reply), expected);
+
+        let newer = Reply::negotiate(VERSION + 1, 0, NO_HELO);
+        assert_eq!(
+            newer,
+            Reply::Negotiate {
+                version: VERSION,
+                actions: 0,
+                protocol: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn replies_have_a_length_and_a_code() {
+        assert_eq!(written(&Reply::Accept), [0, 0, 0, 1, b'a']);
+        assert_eq!(written(&Reply::Continue), [0, 0, 0, 1, b'c']);
+
+        let reject = written(&Reply::Reject("100% spam\r\nhere".to_string()));
+        assert_eq!(&reject[..4], &(reject.len() as u32 - 4).to_be_bytes());
+        assert_eq!(&reject[4..], b"y550 5.7.1 100%% spam  here\0");
+
+        let tempfail = written(&Reply::TempFail("try later".to_string()));
+        assert_eq!(&tempfail[4..], b"y451 4.7.1 try later\0");
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use anyhow::Result;
+use structopt::clap::ErrorKind::{HelpDisplayed, VersionDisplayed};
+use structopt::StructOpt;
+
+use libdnscheck::{
+    helo_domain, parse_nameserver, sender_domain, Checker, Config, Connection, Decision,
+    ListDefinition, Listen, Output, Overrides, Query, ResolverKind,
+};
+
+/// Requests longer than this are either broken or hostile.
+const MAX_REQUEST: u64 = 64 * 1024;
+
+fn main() {
+    if let Err(err) = main_() {
+        if let Some(err) = err.downcast_ref::<structopt::clap::Error>() {
+            let out = io::stdout();
+            writeln!(&mut out.lock(), "{}", err.message).expect("Error writing Error to stdout");
+            if matches!(err.kind, HelpDisplayed | VersionDisplayed) {
+                std::process::exit(0)
+            }
+        } else {
+            eprintln!("Error: {:?}", err);
+        }
+        std::process::exit(1)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt()]
+struct Arguments {
+    #[structopt(
+        short,
+        long,
+        help = "Where to listen: unix:/path, inet:port@host or host:port"
+    )]
+    /// The socket to listen for Postfix on: `unix:/var/spool/postfix/private/dabl' for a Unix
+    /// socket, or `inet:10040@localhost' or `localhost:10040' for TCP.
+    listen: Listen,
+    #[structopt(short, long, number_of_values = 1, help = "A DNS block list")]
+    /// A DNS block list, optionally followed by return codes as for dabl.
+    block: Vec<ListDefinition>,
+    #[structopt(short, long, number_of_values = 1, help = "A DNS allow list")]
+    /// A DNS allow list, optionally followed by return codes as for dabl.  Mail from an address on
+    /// an allow list isn't checked against any block lists.
+    allow: Vec<ListDefinition>,
+    #[structopt(
+        long = "set",
+        number_of_values = 1,
+        help = "A named set of lists from the configuration file"
+    )]
+    /// Check the allow and block lists in a set defined in the configuration file.  If you don't
+    /// give any lists at all, we'll use the set called `default' if there is one.
+    sets: Vec<String>,
+    #[structopt(long, parse(from_os_str), help = "Read configuration from this file")]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "Reject mail that scores at least this much")]
+    /// Add up the weights of the block lists a client is found in, and only reject it if its
+    /// score reaches this threshold.  Without a threshold, any listing is enough.
+    threshold: Option<f64>,
+    #[structopt(long, help = "Also check the domain given in HELO")]
+    helo: bool,
+    #[structopt(long, help = "Also check the domain of the envelope sender")]
+    sender: bool,
+    #[structopt(short, long, help = "How many lookups to run at once [default: 8]")]
+    jobs: Option<usize>,
+    #[structopt(long, help = "Seconds to wait for each lookup [default: 30]")]
+    timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "How to resolve names: auto, resolved or system [default: auto]"
+    )]
+    resolver: Option<ResolverKind>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = parse_nameserver),
+        conflicts_with = "resolver",
+        help = "Send queries directly to this nameserver"
+    )]
+    nameserver: Vec<SocketAddr>,
+    #[structopt(short, long, help = "Log every decision, not just rejections")]
+    verbose: bool,
+}
+
+/// The lists and settings we answer every request with.
+struct Policy {
+    checker: Checker,
+    allow: Vec<ListDefinition>,
+    block: Vec<ListDefinition>,
+    threshold: Option<f64>,
+    helo: bool,
+    sender: bool,
+    verbose: bool,
+}
+
+fn main_() -> Result<()> {
+    let args: Arguments = Arguments::from_args_safe()?;
+
+    let config = Config::load(args.config.as_deref())?;
+    let defaults = &config.defaults;
+
+    let (allow, block) = config.lists(&args.sets, &args.allow, &args.block)?;
+
+    let checker = config.checker(
+        Output::Normal,
+        Overrides {
+            jobs: args.jobs,
+            timeout: args.timeout,
+            resolver: args.resolver,
+            nameservers: args.nameserver,
+            ..Overrides::default()
+        },
+    )?;
+
+    let policy = Policy {
+        checker,
+        allow,
+        block,
+        threshold: args.threshold.or(defaults.threshold),
+        helo: args.helo,
+        sender: args.sender,
+        verbose: args.verbose,
+    };
+
+    args.listen.serve(move |stream| serve(&policy, stream))?;
+    Ok(())
+}
+
+/// Answers Postfix's requests until it closes the connection.  Postfix keeps connections open
+/// between requests, so there may be many.
+fn serve(policy: &Policy, stream: &mut dyn Connection) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = read_request(&mut reader)? {
+        let action = policy.action(&request);
+        let stream = reader.get_mut();
+        write!(stream, "action={}\n\n", action)?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+/// Reads `name=value` lines up to the blank line that ends a request, or nothing if Postfix has
+/// closed the connection.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<HashMap<String, String>>> {
+    let mut request = HashMap::new();
+    let mut reader = reader.take(MAX_REQUEST);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            if request.is_empty() && reader.limit() > 0 {
+                return Ok(None);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "incomplete policy request",
+            ));
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() {
+            return Ok(Some(request));
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            request.insert(name.to_string(), value.to_string());
+        }
+    }
+}
+
+impl Policy {
+    /// Checks the client's address, and optionally its HELO and sender domains, stopping at the
+    /// first thing that's on a block list, or at the address if it's on an allow list.
+    fn action(&self, request: &HashMap<String, String>) -> String {
+        let attribute = |name: &str| request.get(name).map(String::as_str).unwrap_or_default();
+        let client = attribute("client_address");
+        let helo = attribute("helo_name");
+        let sender = attribute("sender");
+
+        let mut queries = vec![];
+        if let Ok(address) = client.parse::<IpAddr>() {
+            queries.push(Query::Address(address));
+        }
+        if self.helo {
+            queries.extend(helo_domain(helo).map(Query::Domain));
+        }
+        if self.sender {
+            queries.extend(sender_domain(sender).map(Query::Domain));
+        }
+
+        // A lookup that failed for one query doesn't stop another from being listed
+        let mut deferred = None;
+        for query in queries {
+            let report = self.checker.check(query, &self.allow, &self.block, true);
+            for error in report.lists.iter().filter_map(|l| l.error.as_ref()) {
+                eprintln!("Warning: {}", error);
+            }
+            let decision = report.decide(self.threshold);
+            if self.verbose {
+                eprintln!("{}: {} {:?}", client, report.query, decision);
+            }
+            match decision {
+                // Anyone can claim a HELO name or a sender, so only the address can vouch for
+                // the rest of the request
+                Decision::Allow if matches!(query, Query::Address(_)) => {
+                    return "DUNNO".to_string()
+                }
+                Decision::Allow => {}
+                Decision::Block(text) => {
+                    eprintln!("{}: {}", client, text);
+                    return format!("REJECT {}", one_line(&text));
+                }
+                Decision::Defer(text) => {
+                    deferred.get_or_insert(text);
+                }
+                Decision::Pass => {}
+            }
+        }
+        match deferred {
+            Some(text) => {
+                eprintln!("{}: {}", client, text);
+                format!("DEFER_IF_PERMIT {}", one_line(&text))
+            }
+            None => "DUNNO".to_string(),
+        }
+    }
+}
+
+/// A line break would end the response early.
+fn one_line(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use libdnscheck::{DnsCheckError, Family, Limits, Resolver};
+
+    /// Answers from a table instead of DNS.
+    #[derive(Default)]
+    struct Mock {
+        answers: HashMap<String, Vec<IpAddr>>,
+        failing: Vec<String>,
+    }
+
+    impl Mock {
+        fn listed(mut self, hostname: &str) -> Mock {
+            let answer = "127.0.0.2".parse().unwrap();
+            self.answers.insert(hostname.to_string(), vec![answer]);
+            self
+        }
+
+        fn failing(mut self, hostname: &str) -> Mock {
+            self.failing.push(hostname.to_string());
+            self
+        }
+    }
+
+    impl Resolver for Mock {
+        fn resolve(&self, hostname: &str, _: Family) -> Result<Vec<IpAddr>, DnsCheckError> {
+            if self.failing.iter().any(|name| name == hostname) {
+                return Err(DnsCheckError::Timeout(hostname.to_string()));
+            }
+            Ok(self.answers.get(hostname).cloned().unwrap_or_default())
+        }
+
+        fn resolve_txt(&self, _: &str) -> Result<Vec<String>, DnsCheckError> {
+            Ok(vec![])
+        }
+    }
+
+    fn policy(mock: Mock) -> Policy {
+        let limits = Limits {
+            parallelism: 1,
+            timeout: Duration::from_secs(1),
+        };
+        Policy {
+            checker: Checker::with_resolver(Output::Quiet, limits, Box::new(mock)),
+            allow: vec!["wl.example".parse().unwrap()],
+            block: vec!["bl.example".parse().unwrap()],
+            threshold: None,
+            helo: true,
+            sender: false,
+            verbose: false,
+        }
+    }
+
+    fn request(attributes: &[(&str, &str)]) -> HashMap<String, String> {
+        attributes
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn read(buf: &[u8]) -> io::Result<Option<HashMap<String, String>>> {
+        read_request(&mut &buf[..])
+    }
+
+    #[test]
+    fn requests_are_attributes_up_to_a_blank_line() {
+        let mut buf = &b"request=smtpd_access_policy\nclient_address=192.0.2.1\r\n\
+            sender=<a=b@example.com>\nnonsense\n\nclient_address=192.0.2.2\n\n"[..];
+        let first = read_request(&mut buf).unwrap().unwrap();
+        assert_eq!(first.len(), 3);
+        assert_eq!(first["request"], "smtpd_access_policy");
+        assert_eq!(first["client_address"], "192.0.2.1");
+        assert_eq!(first["sender"], "<a=b@example.com>");
+
+        let second = read_request(&mut buf).unwrap().unwrap();
+        assert_eq!(second, request(&[("client_address", "192.0.2.2")]));
+        assert!(read_request(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn an_empty_request_is_still_a_request() {
+        assert_eq!(read(b"\n").unwrap(), Some(HashMap::new()));
+    }
+
+    #[test]
+    fn a_request_cut_short_is_an_error() {
+        let error = read(b"client_address=192.0.2.1\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(read(b"client_address=192.0.2.1").is_err());
+    }
+
+    #[test]
+    fn requests_are_limited_in_size() {
+        let mut buf = b"client_address=192.0.2.1\n".to_vec();
+        let padding = format!("x={}\n", "y".repeat(1000));
+        while buf.len() as u64 <= MAX_REQUEST {
+            buf.extend_from_slice(padding.as_bytes());
+        }
+        buf.extend_from_slice(b"\n");
+        let error = read(&buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Anything shorter is fine
+        let mut buf = format!("x={}\n\n", "y".repeat(MAX_REQUEST as usize - 4)).into_bytes();
+        assert_eq!(buf.len() as u64, MAX_REQUEST);
+        assert!(read(&buf).unwrap().is_some());
+        buf.insert(0, b'x');
+        assert!(read(&buf).is_err());
+    }
+
+    #[test]
+    fn unlisted_clients_are_left_to_the_rest_of_postfix() {
+        let policy = policy(Mock::default());
+        let request = request(&[("client_address", "192.0.2.1"), ("helo_name", "mx.example")]);
+        assert_eq!(policy.action(&request), "DUNNO");
+    }
+
+    #[test]
+    fn listed_clients_are_rejected() {
+        let policy = policy(Mock::default().listed("1.2.0.192.bl.example."));
+        let request = request(&[("client_address", "192.0.2.1")]);
+        assert_eq!(
+            policy.action(&request),
+            "REJECT 192.0.2.1 is listed by bl.example"
+        );
+    }
+
+    #[test]
+    fn listed_helo_names_are_rejected() {
+        let policy = policy(Mock::default().listed("mx.example.bl.example."));
+        let request = request(&[("client_address", "192.0.2.1"), ("helo_name", "mx.example")]);
+        assert_eq!(
+            policy.action(&request),
+            "REJECT mx.example is listed by bl.example"
+        );
+    }
+
+    #[test]
+    fn allow_listed_clients_skip_everything_else() {
+        let mock = Mock::default()
+            .listed("1.2.0.192.wl.example.")
+            .listed("mx.example.bl.example.");
+        let request = request(&[("client_address", "192.0.2.1"), ("helo_name", "mx.example")]);
+        assert_eq!(policy(mock).action(&request), "DUNNO");
+    }
+
+    #[test]
+    fn failed_lookups_defer_unless_something_is_listed() {
+        let mock = Mock::default().failing("1.2.0.192.bl.example.");
+        let request = request(&[("client_address", "192.0.2.1"), ("helo_name", "mx.example")]);
+        assert_eq!(
+            policy(mock).action(&request),
+            "DEFER_IF_PERMIT Unable to check 192.0.2.1 against bl.example, try again later"
+        );
+
+        let mock = Mock::default()
+            .failing("1.2.0.192.bl.example.")
+            .listed("mx.example.bl.example.");
+        assert_eq!(
+            policy(mock).action(&request),
+            "REJECT mx.example is listed by bl.example"
+        );
+    }
+
+    #[test]
+    fn requests_without_a_client_address_check_the_rest() {
+        let address = policy(Mock::default().listed("1.2.0.192.bl.example."));
+        assert_eq!(address.action(&request(&[])), "DUNNO");
+        assert_eq!(
+            address.action(&request(&[("client_address", "unknown")])),
+            "DUNNO"
+        );
+
+        let helo = policy(Mock::default().listed("mx.example.bl.example."));
+        let request = request(&[("helo_name", "mx.example")]);
+        assert_eq!(
+            helo.action(&request),
+            "REJECT mx.example is listed by bl.example"
+        );
+    }
+
+    #[test]
+    fn responses_stay_on_one_line() {
+        assert_eq!(one_line("listed\r\nby us"), "listed  by us");
+    }
+}
@@ -1,38 +1,49 @@
 use std::io;
 use std::io::Write;
-use std::net::IpAddr;
-use std::ops::Deref;
-use std::str::FromStr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use anyhow::Result;
-use lazy_static::lazy_static;
+use structopt::clap::ArgMatches;
+use structopt::clap::ErrorKind::{HelpDisplayed, VersionDisplayed};
 use structopt::StructOpt;
 
 use libdnscheck::Output::Normal;
-use libdnscheck::{lookup, Query};
-
-lazy_static! {
-    // These are the defaults from the Debian package
-    static ref BASE_SOURCES: Vec<&'static str> = vec![
-        "sbl.spamhaus.org",
-        "xbl.spamhaus.org",
-        "pbl.spamhaus.org",
-        "bl.spamcop.net",
-        "psbl.surriel.com",
-        "dul.dnsbl.sorbs.net",
-    ];
-}
+use libdnscheck::{
+    parse_nameserver, read_queries, Config, DnsCheckError, ExitStatus, Format, ListDefinition,
+    Overrides, Query, QueryReport, Role, StopAfter, Tally,
+};
+
+// These are the defaults from the Debian package, which a set called `rblcheck' in the
+// configuration file replaces
+const BASE_SOURCES: &[&str] = &[
+    "sbl.spamhaus.org",
+    "xbl.spamhaus.org",
+    "pbl.spamhaus.org",
+    "bl.spamcop.net",
+    "psbl.surriel.com",
+    "dul.dnsbl.sorbs.net",
+];
+const CONFIG_SET: &str = "rblcheck";
 
 fn main() {
+    // We need to know how to exit even if we can't make sense of the rest of the arguments
+    let exit_status = std::env::args_os().any(|arg| arg == "--exit-status");
     let err = main_().unwrap_err();
     if let Some(err) = err.downcast_ref::<structopt::clap::Error>() {
         let out = io::stdout();
         writeln!(&mut out.lock(), "{}", err.message).expect("Error writing Error to stdout");
+        if matches!(err.kind, HelpDisplayed | VersionDisplayed) {
+            std::process::exit(0)
+        }
     } else {
         eprintln!("Error: {:?}", err);
     }
-    // We exit with a "success" on error, as the error code is the count of the number of lists hit
-    // so `0` is the fail-safe
+    if exit_status {
+        std::process::exit(ExitStatus::TotalFailure.code())
+    }
+    // Otherwise we exit with a "success" on error, as the error code is the count of the number of
+    // lists hit so `0` is the fail-safe
     std::process::exit(0)
 }
 
@@ -59,55 +70,377 @@ struct Arguments {
     match_one: bool,
     #[structopt(short, help = "List default DNSBL services to check")]
     list: bool,
-    #[structopt(short, help = "Clear the current list of DNSBL services")]
-    clear: bool,
+    #[structopt(
+        short,
+        parse(from_occurrences),
+        help = "Clear the current list of DNSBL services"
+    )]
+    clear: u32,
     #[structopt(
         short,
         number_of_values = 1,
         help = "Toggle a service to the DNSBL services list"
     )]
-    services: Vec<String>,
+    services: Vec<ListDefinition>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(try_from_str = parse_nameserver),
+        help = "Send queries directly to this nameserver"
+    )]
+    nameserver: Vec<SocketAddr>,
+    #[structopt(long, parse(from_os_str), help = "Read configuration from this file")]
+    config: Option<PathBuf>,
     #[structopt(
-        help = "An IP address to look up; specify `-' to read multiple addresses from standard input"
+        long,
+        default_value = "text",
+        help = "How to print results: text, json or jsonl"
+    )]
+    format: Format,
+    #[structopt(
+        long,
+        help = "Exit with 0 if clean, 1 if listed, 3 if some lookups failed or 4 if all did"
+    )]
+    exit_status: bool,
+    #[structopt(
+        help = "An IP address or network to look up; specify `-' to read multiple addresses from standard input"
     )]
     addresses: Vec<String>,
 }
 
 fn main_() -> Result<()> {
-    let args: Arguments = Arguments::from_args_safe()?;
+    let matches = Arguments::clap().get_matches_safe()?;
+    let args = Arguments::from_clap(&matches);
 
-    let base_sources = if args.clear {
-        vec![]
+    let config = Config::load(args.config.as_deref())?;
+
+    let base_sources = if config.has_set(CONFIG_SET) {
+        config.set(CONFIG_SET, Role::Block)?
     } else {
-        BASE_SOURCES.clone()
+        BASE_SOURCES
+            .iter()
+            .copied()
+            .map(ListDefinition::from)
+            .collect()
     };
+    let sources = services(&matches, &args, base_sources);
 
-    let extra_sources: Vec<&str> = args.services.iter().map(Deref::deref).collect();
+    if args.list {
+        for source in &sources {
+            println!("{}", source);
+        }
+        std::process::exit(0);
+    }
 
-    let params: Vec<&str> = args.addresses.iter().map(Deref::deref).collect();
+    let stop = if args.match_one {
+        StopAfter::QueryFound
+    } else {
+        StopAfter::Never
+    };
+    let overrides = Overrides {
+        nameservers: args.nameserver.clone(),
+        ..Overrides::default()
+    };
+    let checker = config.checker(Normal, overrides)?.with_stop_after(stop);
 
-    println!(
-        "Base: {:?}, Extra: {:?}, Queries: {:?}",
-        base_sources, extra_sources, params
-    );
+    let reasons = args.text || args.format != Format::Text;
 
-    let queries: Vec<Query> = params
+    let mut result = 0;
+    let mut tally = Tally::default();
+    let mut reports = vec![];
+    let mut report = |query: Result<Query, DnsCheckError>| -> Result<()> {
+        let query = match query {
+            Ok(query) => query,
+            // One bad line on standard input shouldn't stop us checking the rest
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                tally.fail();
+                return Ok(());
+            }
+        };
+        let report = checker.check(query, &[], &sources, reasons);
+        let hits = report.blocks().len() as i32;
+        result += hits;
+        tally.add(&report, hits > 0);
+        match args.format {
+            Format::Text => print_text(
+                &mut io::stdout().lock(),
+                report,
+                args.quiet,
+                args.exit_status,
+            )?,
+            Format::JsonLines => println!("{}", serde_json::to_string(&report)?),
+            Format::Json => reports.push(report),
+        }
+        Ok(())
+    };
+    // Turn down a network we can't check before we've printed anything for the others
+    let queries = args
+        .addresses
         .iter()
-        .map(|&p| match IpAddr::from_str(p) {
-            Ok(ip) => Query::Address(ip),
-            _ => Query::Domain(p),
+        .map(|param| match param.as_str() {
+            "-" => Ok(None),
+            param => Query::parse(param, &sources).map(Some),
         })
-        .collect();
+        .collect::<Result<Vec<_>, _>>()?;
+    for query in queries {
+        match query {
+            Some(query) => report(Ok(query))?,
+            None => {
+                for line in read_queries(io::stdin().lock()) {
+                    let line = line?;
+                    report(Query::parse(&line, &sources))?;
+                }
+            }
+        }
+    }
+    if args.format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
 
-    let result = queries
-        .iter()
-        .flat_map(|query| {
-            let sources = base_sources.iter().chain(extra_sources.iter());
-            sources.map(move |&source| lookup(source, query, &Normal))
-        })
-        .fold::<Result<i32>, _>(Ok(0), |r, i| if i?.found { r.map(|n| n + 1) } else { r })?;
+    if args.exit_status {
+        std::process::exit(tally.status().code());
+    }
+    // Exit codes only have eight bits, and a count that wrapped to 0 would look clean
+    std::process::exit(result.min(255));
+}
 
-    println!("Hit {} lists", result);
+enum ServiceChange<'a> {
+    Clear,
+    Toggle(&'a ListDefinition),
+}
 
-    std::process::exit(result);
+/// Works out which services to check, applying `-c` and `-s` in the order they were given.
+fn services(
+    matches: &ArgMatches,
+    args: &Arguments,
+    base: Vec<ListDefinition>,
+) -> Vec<ListDefinition> {
+    let mut changes: Vec<(usize, ServiceChange)> = vec![];
+    if args.clear > 0 {
+        if let Some(indices) = matches.indices_of("clear") {
+            changes.extend(indices.map(|i| (i, ServiceChange::Clear)));
+        }
+    }
+    if let Some(indices) = matches.indices_of("services") {
+        changes.extend(
+            indices
+                .zip(&args.services)
+                .map(|(i, s)| (i, ServiceChange::Toggle(s))),
+        );
+    }
+    changes.sort_by_key(|(index, _)| *index);
+    apply(base, changes.into_iter().map(|(_, change)| change))
+}
+
+/// Changes the services to check just like the original: `-c` clears every service we've seen so
+/// far, and `-s` removes a service if it's already in the list, otherwise adds it.
+fn apply<'a>(
+    base: Vec<ListDefinition>,
+    changes: impl IntoIterator<Item = ServiceChange<'a>>,
+) -> Vec<ListDefinition> {
+    let mut services = base;
+    for change in changes {
+        match change {
+            ServiceChange::Clear => services.clear(),
+            ServiceChange::Toggle(service) => {
+                let name = same_name(&service.name);
+                match services.iter().position(|s| same_name(&s.name) == name) {
+                    Some(index) => {
+                        services.remove(index);
+                    }
+                    None => services.push(service.clone()),
+                }
+            }
+        }
+    }
+    services
+}
+
+/// DNS names don't care about case or a trailing dot, so neither should toggling a service.
+fn same_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Prints a line for each service in the same format as the original: `<address> [not ]RBL
+/// filtered by <service>`, followed by `: <reason>` with `-t`.  In quiet mode we only print the
+/// address, once for each service it's listed in.  Unless we've been asked to keep going, we stop
+/// at the first error other than a refusal.
+fn print_text(
+    out: &mut impl Write,
+    report: QueryReport,
+    quiet: bool,
+    keep_going: bool,
+) -> Result<()> {
+    for list in report.lists {
+        match list.error {
+            Some(e @ DnsCheckError::Refused { .. }) => {
+                eprintln!("Warning: {}", e);
+                continue;
+            }
+            Some(e) if keep_going => {
+                eprintln!("Warning: {}", e);
+                continue;
+            }
+            Some(e) => return Err(e.into()),
+            None => {}
+        }
+        if list.skipped {
+            continue;
+        }
+
+        if !list.found {
+            if !quiet {
+                writeln!(out, "{} not RBL filtered by {}", report.query, list.list)?;
+            }
+            continue;
+        }
+
+        let line = if quiet {
+            report.query.clone()
+        } else {
+            format!("{} RBL filtered by {}", report.query, list.list)
+        };
+        if list.reason.is_empty() {
+            writeln!(out, "{}", line)?;
+        } else {
+            writeln!(out, "{}: {}", line, list.reason.join(" "))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use libdnscheck::ListReport;
+
+    fn lists(names: &[&str]) -> Vec<ListDefinition> {
+        names.iter().map(|name| ListDefinition::new(name)).collect()
+    }
+
+    fn names(lists: &[ListDefinition]) -> Vec<&str> {
+        lists.iter().map(|l| l.name.as_str()).collect()
+    }
+
+    #[test]
+    fn services_after_clearing_replace_the_base_ones() {
+        let base = lists(&["bl.example", "other.example"]);
+        let added = ListDefinition::new("new.example");
+        let changes = vec![ServiceChange::Clear, ServiceChange::Toggle(&added)];
+        assert_eq!(names(&apply(base, changes)), vec!["new.example"]);
+    }
+
+    #[test]
+    fn clearing_forgets_services_added_before_it() {
+        let base = lists(&["bl.example"]);
+        let added = ListDefinition::new("new.example");
+        let changes = vec![ServiceChange::Toggle(&added), ServiceChange::Clear];
+        assert_eq!(names(&apply(base, changes)), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn services_we_already_have_are_toggled_off() {
+        let base = lists(&["bl.example", "other.example"]);
+        let again = ListDefinition::new("BL.Example.");
+        let changes = vec![ServiceChange::Toggle(&again)];
+        assert_eq!(names(&apply(base, changes)), vec!["other.example"]);
+
+        let base = lists(&["bl.example"]);
+        let changes = vec![ServiceChange::Toggle(&again), ServiceChange::Toggle(&again)];
+        assert_eq!(names(&apply(base, changes)), vec!["BL.Example."]);
+    }
+
+    #[test]
+    fn options_apply_in_the_order_they_were_given() {
+        let run = |options: &[&str]| {
+            let args = Some("rblcheck").iter().chain(options);
+            let matches = Arguments::clap().get_matches_from_safe(args).unwrap();
+            let args = Arguments::from_clap(&matches);
+            let services = services(&matches, &args, lists(&["bl.example"]));
+            names(&services).join(" ")
+        };
+        assert_eq!(
+            run(&["-s", "a.example", "-c", "-s", "b.example"]),
+            "b.example"
+        );
+        assert_eq!(
+            run(&["-c", "-s", "a.example", "-s", "bl.example"]),
+            "a.example bl.example"
+        );
+        assert_eq!(run(&["-s", "bl.example", "-s", "a.example"]), "a.example");
+        assert_eq!(run(&["-s", "a.example", "-c"]), "");
+    }
+
+    fn list(name: &str, found: bool, reason: &[&str]) -> ListReport {
+        ListReport {
+            list: name.to_string(),
+            role: Role::Block,
+            found,
+            skipped: false,
+            answers: vec![],
+            labels: vec![],
+            score: 0.0,
+            reason: reason.iter().map(|r| r.to_string()).collect(),
+            elapsed: Duration::from_millis(1),
+            ttl: None,
+            listed: vec![],
+            error: None,
+        }
+    }
+
+    fn report() -> QueryReport {
+        let mut skipped = list("skipped.example", false, &[]);
+        skipped.skipped = true;
+        QueryReport {
+            query: "192.0.2.1".to_string(),
+            lists: vec![
+                list("clean.example", false, &[]),
+                list("bl.example", true, &[]),
+                skipped,
+                list("txt.example", true, &["Listed,", "see", "example.com"]),
+            ],
+        }
+    }
+
+    fn text(report: QueryReport, quiet: bool, keep_going: bool) -> Result<String> {
+        let mut out = vec![];
+        print_text(&mut out, report, quiet, keep_going)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn text_is_in_the_original_format() {
+        assert_eq!(
+            text(report(), false, false).unwrap(),
+            "192.0.2.1 not RBL filtered by clean.example\n\
+             192.0.2.1 RBL filtered by bl.example\n\
+             192.0.2.1 RBL filtered by txt.example: Listed, see example.com\n"
+        );
+    }
+
+    #[test]
+    fn quiet_text_is_only_listed_addresses() {
+        assert_eq!(
+            text(report(), true, false).unwrap(),
+            "192.0.2.1\n192.0.2.1: Listed, see example.com\n"
+        );
+    }
+
+    #[test]
+    fn failed_lookups_stop_us_unless_we_keep_going() {
+        let failing = || {
+            let mut report = report();
+            report.lists[0].error = Some(DnsCheckError::Timeout("timed out".to_string()));
+            report
+        };
+        assert!(text(failing(), false, false).is_err());
+        assert_eq!(
+            text(failing(), false, true).unwrap(),
+            "192.0.2.1 RBL filtered by bl.example\n\
+             192.0.2.1 RBL filtered by txt.example: Listed, see example.com\n"
+        );
+    }
 }